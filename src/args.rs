@@ -1,6 +1,12 @@
+use chrono::{DateTime, TimeZone, Utc};
 use clap::Clap;
 use std::path::PathBuf;
 
+use crate::output::Format;
+use crate::providers::ProviderKind;
+use crate::storage::StorageKind;
+use crate::TradeType;
+
 /// Provides portfolio services: tracks trades and position, automatically downloads prices
 /// & reports on portfolio risk factors.
 #[derive(Clap)]
@@ -10,6 +16,18 @@ pub struct Opts {
     #[clap(short, long)]
     pub directory: Option<PathBuf>,
 
+    /// Currency to express amounts in, overrides the one in the config file
+    #[clap(long)]
+    pub currency: Option<String>,
+
+    /// Skip and report the rows that can't be read instead of stopping
+    #[clap(long)]
+    pub lenient: bool,
+
+    /// Output format (table, json, csv, tsv)
+    #[clap(long, default_value = "table")]
+    pub format: Format,
+
     #[clap(short, long)]
     pub quiet: bool,
     /// Verbose mode (-v, -vv, -vvv, etc)
@@ -42,6 +60,72 @@ pub enum SubCommand {
         /// Includes just trades with name containing the string
         name_substring: Option<String>,
     },
+    /// Record a trade at the end of the trades file
+    Add {
+        /// Ask for the fields not given, completing account and stock names
+        #[clap(short, long)]
+        interactive: bool,
+
+        /// Type of trade (Buy, Sell, TrIn, TrOut, Div, Split)
+        trade_type: Option<TradeType>,
+
+        /// Account the trade is made in
+        #[clap(short, long)]
+        account: Option<String>,
+
+        /// Name of the stock, as in the stocks file
+        #[clap(short, long)]
+        stock: Option<String>,
+
+        /// Number of units traded
+        #[clap(short, long)]
+        units: Option<f64>,
+
+        /// Price of one unit in the traded currency
+        #[clap(short, long)]
+        price: Option<f64>,
+
+        /// Fees in the traded currency
+        #[clap(short, long)]
+        fees: Option<f64>,
+
+        /// New units for each old one, for splits
+        #[clap(long)]
+        split: Option<f64>,
+
+        /// Date of the trade (YYYY/MM/DD) [default: today]
+        #[clap(long, parse(try_from_str = parse_date))]
+        date: Option<DateTime<Utc>>,
+
+        /// Rate from the traded currency to the base one [default: from the prices file]
+        #[clap(long)]
+        rate: Option<f64>,
+
+        /// Acquisition date of the lot a sale closes (YYYY/MM/DD)
+        #[clap(long)]
+        lot: Option<String>,
+    },
+    /// List the changes made to the trades and stocks files
+    History {
+        /// Show the lines each change removed and added
+        #[clap(short, long)]
+        diff: bool,
+    },
+    /// Revert the last changes made to the trades and stocks files
+    Undo {
+        /// Number of changes to revert
+        #[clap(default_value = "1")]
+        n: usize,
+        /// Reverts files changed since their last recorded change too
+        #[clap(short, long)]
+        force: bool,
+    },
+    /// Copy the portfolio to another storage and use it from then on
+    Migrate {
+        /// Storage to copy the portfolio to (tsv, sqlite)
+        #[clap(long)]
+        to: StorageKind,
+    },
     /// List all stocks known to the program
     Stocks {
         /// Edit the stocks file by opening the default editor
@@ -61,20 +145,68 @@ pub enum SubCommand {
         #[clap(short, long)]
         separate_cash: bool,
 
+        /// Aggregate positions in the same stock across accounts
+        #[clap(short = 'g', long)]
+        aggregate: bool,
+
+        /// Show the portfolio as it was at the end of this date (YYYY/MM/DD)
+        #[clap(long, parse(try_from_str = parse_date))]
+        as_of: Option<DateTime<Utc>>,
+
         /// Field to sort positions on
         #[clap(subcommand)]
         sort_by: Option<SortField>,
     },
     /// Report on the portfolio exposure to various risks
     Report {
+        /// Aggregate positions in the same stock across accounts
+        #[clap(short = 'g', long)]
+        aggregate: bool,
+
+        /// Report on the portfolio as it was at the end of this date (YYYY/MM/DD)
+        #[clap(long, parse(try_from_str = parse_date))]
+        as_of: Option<DateTime<Utc>>,
+
         /// Type of report to generate
         #[clap(subcommand)]
         report_type: ReportType,
     },
+    /// Realized gains for the lots closed in a tax year
+    Gains {
+        /// Tax year
+        #[clap(short, long)]
+        year: i32,
+
+        /// Emit CSV instead of a table, same as --format csv
+        #[clap(short, long)]
+        csv: bool,
+    },
     /// Update prices of all stock owned using the Yahoo finance API
-    UpdatePrices {},
+    UpdatePrices {
+        /// Download the daily prices since the first trade of each stock
+        #[clap(short, long)]
+        backfill: bool,
+
+        /// Provider for stocks with no 'Provider' column and for currencies (Yahoo, File)
+        #[clap(short, long)]
+        provider: Option<ProviderKind>,
+
+        /// Directory with the quote files for the File provider [default: <directory>/quotes]
+        #[clap(long)]
+        quotes_dir: Option<PathBuf>,
+    },
     /// Total value of the portfolio
-    Total {},
+    Total {
+        /// Value of the portfolio at the end of this date (YYYY/MM/DD)
+        #[clap(long, parse(try_from_str = parse_date))]
+        as_of: Option<DateTime<Utc>>,
+    },
+    /// Time-weighted and money-weighted returns of the portfolio, its accounts and groups
+    Performance {
+        /// Returns for the periods ending on this date (YYYY/MM/DD)
+        #[clap(long, parse(try_from_str = parse_date))]
+        as_of: Option<DateTime<Utc>>,
+    },
 }
 
 #[derive(Clap)]
@@ -96,6 +228,8 @@ pub enum SortField {
 
 #[derive(Clap)]
 pub enum ReportType {
+    /// By account
+    Account,
     /// By currency
     Currency,
     /// By asset type
@@ -108,6 +242,11 @@ pub enum ReportType {
     Tags,
 }
 
+pub fn parse_date(s: &str) -> Result<DateTime<Utc>, String> {
+    Utc.datetime_from_str(&format!("{} 00:00:00", s), "%Y/%m/%d %H:%M:%S")
+        .map_err(|e| format!("{} is not a YYYY/MM/DD date: {}", s, e))
+}
+
 pub fn parse_args() -> Opts {
     let opts = Opts::parse();
     if opts.directory.is_none() {
@@ -0,0 +1,124 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use serde::Serialize;
+
+use crate::prices::PriceHistory;
+use crate::{Stocks, Trade, TradeType, STOCKS_FILE, TRADES_FILE};
+
+/// A problem found in a portfolio file, at the line it was read from.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct CheckError {
+    pub file: String,
+    pub line: u64,
+    pub message: String,
+}
+
+impl fmt::Display for CheckError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.file, self.line, self.message)
+    }
+}
+
+fn error(file: &str, line: u64, message: String) -> CheckError {
+    CheckError {
+        file: file.to_string(),
+        line,
+        message,
+    }
+}
+
+/// Stocks defined more than once, and traded stocks with a ticker that has no prices.
+pub fn check_stocks(
+    stocks: &[(u64, Stocks)],
+    trades: &[(u64, Trade)],
+    history: &PriceHistory,
+) -> Vec<CheckError> {
+    let mut errors = Vec::new();
+    let mut seen: HashMap<&str, u64> = HashMap::new();
+    let traded: HashSet<&str> = trades.iter().map(|(_, t)| &t.stock[..]).collect();
+
+    for (line, s) in stocks {
+        if let Some(first) = seen.get(&s.name[..]) {
+            let message = format!("Stock {} is already defined at line {}", s.name, first);
+            errors.push(error(STOCKS_FILE, *line, message));
+            continue;
+        }
+        seen.insert(&s.name, *line);
+
+        if let Some(ticker) = s.ticker.as_ref().filter(|_| traded.contains(&s.name[..])) {
+            if history.latest(ticker).is_none() {
+                let message = format!("No prices for ticker {} of {}", ticker, s.name);
+                errors.push(error(STOCKS_FILE, *line, message));
+            }
+        }
+    }
+    errors
+}
+
+/// Trades of unknown stocks or accounts without cash, out of order, incomplete or leaving
+/// negative units in a position.
+pub fn check_trades(stocks: &HashMap<String, Stocks>, trades: &[(u64, Trade)]) -> Vec<CheckError> {
+    let mut errors = Vec::new();
+    let mut err = |line: u64, message: String| errors.push(error(TRADES_FILE, line, message));
+    let mut no_cash = HashSet::new();
+    let mut units: HashMap<(&str, &str), f64> = HashMap::new();
+    let mut previous: Option<(u64, &Trade)> = None;
+
+    for (line, t) in trades {
+        let line = *line;
+        if !stocks.contains_key(&t.stock) {
+            err(line, format!("Unknown stock {}", t.stock));
+        }
+        let cash = format!("Cash{}", t.account);
+        if !t.stock.contains("Cash") && !stocks.contains_key(&cash) && no_cash.insert(cash.clone())
+        {
+            err(
+                line,
+                format!("No {} stock for the cash of account {}", cash, t.account),
+            );
+        }
+
+        if let Some((pline, p)) = previous.filter(|(_, p)| t.date < p.date) {
+            err(
+                line,
+                format!(
+                    "Trade on {} is before the one on {} at line {}",
+                    t.date.format("%Y/%m/%d"),
+                    p.date.format("%Y/%m/%d"),
+                    pline
+                ),
+            );
+        }
+        previous = Some((line, t));
+
+        match t.r#type {
+            TradeType::Split if t.split <= 0.0 => {
+                err(line, format!("Split with a ratio of {}", t.split))
+            }
+            TradeType::Buy | TradeType::Sell if t.price.is_none() => {
+                err(line, format!("{:?} without a price", t.r#type))
+            }
+            _ => (),
+        }
+
+        if t.stock.contains("Cash") {
+            continue;
+        }
+        let u = units.entry((&t.account, &t.stock)).or_default();
+        let was_negative = *u < -f64::EPSILON;
+        match t.r#type {
+            TradeType::Split => *u *= t.split,
+            TradeType::Buy | TradeType::TrIn => *u += t.units,
+            TradeType::Sell | TradeType::TrOut => *u -= t.units,
+            TradeType::Div => (),
+        }
+        if *u < -f64::EPSILON && !was_negative {
+            err(
+                line,
+                format!("{} units of {} in account {}", *u, t.stock, t.account),
+            );
+        }
+    }
+    errors
+}
@@ -0,0 +1,120 @@
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, OnceLock, Weak};
+use std::{fs, io};
+
+use fs2::FileExt;
+
+use crate::errors::*;
+
+/// Name of the file locked while a portfolio directory is open.
+pub const LOCK_FILE: &str = ".lock";
+
+/// An advisory lock on a portfolio directory, released when the last store using it is
+/// dropped. Readers share it, a writer holds it alone.
+#[derive(Debug)]
+pub struct DirLock {
+    file: fs::File,
+    exclusive: AtomicBool,
+}
+
+impl DirLock {
+    /// Whether the directory can be changed under this lock.
+    pub fn is_exclusive(&self) -> bool {
+        self.exclusive.load(Ordering::SeqCst)
+    }
+}
+
+impl Drop for DirLock {
+    fn drop(&mut self) {
+        let _ = self.file.unlock();
+    }
+}
+
+// The locks held by this process, so that stores opened on the same directory share one.
+fn locks() -> &'static Mutex<HashMap<PathBuf, Weak<DirLock>>> {
+    static LOCKS: OnceLock<Mutex<HashMap<PathBuf, Weak<DirLock>>>> = OnceLock::new();
+    LOCKS.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+// Takes the lock on the file without waiting, shared or exclusive.
+fn try_lock(file: &fs::File, dir: &Path, exclusive: bool) -> Result<()> {
+    let res = if exclusive {
+        file.try_lock_exclusive()
+    } else {
+        FileExt::try_lock_shared(file)
+    };
+    match res {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == fs2::lock_contended_error().kind() => error_chain::bail!(
+            "The portfolio in {} is in use by another lupo, try again when it is done",
+            dir.to_string_lossy()
+        ),
+        Err(e) => Err(e).chain_err(|| "Can't lock the portfolio directory"),
+    }
+}
+
+/// Locks the directory against other processes, failing at once if one holds it already.
+/// An exclusive lock is for changing the directory, a shared one for reading it while
+/// others may read it too.
+pub fn lock_dir(dir: &Path, exclusive: bool) -> Result<Arc<DirLock>> {
+    let key = dir
+        .canonicalize()
+        .chain_err(|| format!("Can't find directory {}", dir.to_string_lossy()))?;
+    let mut locks = locks().lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(lock) = locks.get(&key).and_then(Weak::upgrade) {
+        // A writer in this process upgrades the lock its readers share.
+        if exclusive && !lock.is_exclusive() {
+            try_lock(&lock.file, dir, true)?;
+            lock.exclusive.store(true, Ordering::SeqCst);
+        }
+        return Ok(lock);
+    }
+
+    let file = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(key.join(LOCK_FILE))
+        .chain_err(|| "Can't create the lock file")?;
+    try_lock(&file, dir, exclusive)?;
+
+    let lock = Arc::new(DirLock {
+        file,
+        exclusive: AtomicBool::new(exclusive),
+    });
+    locks.insert(key, Arc::downgrade(&lock));
+    Ok(lock)
+}
+
+/// Where a file is written before it replaces the original.
+pub fn temp_path(path: &Path) -> PathBuf {
+    let name = path.file_name().map_or("".into(), |n| n.to_string_lossy());
+    path.with_file_name(format!(".{}.tmp", name))
+}
+
+/// Where the copy of a file given to the editor is kept while it is edited.
+pub fn edit_path(path: &Path) -> PathBuf {
+    let name = path.file_name().map_or("".into(), |n| n.to_string_lossy());
+    path.with_file_name(format!(".{}.edit", name))
+}
+
+/// Replaces the file with the contents at once: they are written to a temporary file that
+/// is renamed over it, so readers see either the old file or the new one.
+pub fn write_atomic(path: &Path, contents: &[u8]) -> Result<()> {
+    let temp = temp_path(path);
+    let name = path.to_string_lossy();
+    let write = || -> io::Result<()> {
+        let mut file = fs::File::create(&temp)?;
+        file.write_all(contents)?;
+        file.sync_all()
+    };
+    if let Err(e) = write() {
+        let _ = fs::remove_file(&temp);
+        return Err(e).chain_err(|| format!("Cannot write to file {}", name));
+    }
+    fs::rename(&temp, path).chain_err(|| format!("Cannot replace file {}", name))
+}
@@ -0,0 +1,234 @@
+use dialoguer::{Completion, Confirm, Input, Select};
+
+use lupo::args::parse_date;
+use lupo::errors::*;
+use lupo::{EditChoice, Store, Trade, TradeDraft, TradeType, RATE_CURRENCY};
+
+const TRADE_TYPES: [TradeType; 6] = [
+    TradeType::Buy,
+    TradeType::Sell,
+    TradeType::TrIn,
+    TradeType::TrOut,
+    TradeType::Div,
+    TradeType::Split,
+];
+
+// Completes to the first name starting with the input, ignoring case.
+struct Names<'a>(&'a [String]);
+
+impl Completion for Names<'_> {
+    fn get(&self, input: &str) -> Option<String> {
+        let input = input.to_lowercase();
+        self.0
+            .iter()
+            .find(|n| n.to_lowercase().starts_with(&input))
+            .cloned()
+    }
+}
+
+fn interacted<T>(r: dialoguer::Result<T>) -> Result<T> {
+    r.chain_err(|| "Cannot read from the terminal")
+}
+
+// Asks for a name, completed with Tab. With `known_only` it must be one of the names.
+fn ask_name(prompt: &str, names: &[String], known_only: bool) -> Result<String> {
+    let completion = Names(names);
+    let mut input = Input::<String>::new()
+        .with_prompt(prompt)
+        .completion_with(&completion);
+    if known_only {
+        input = input.validate_with(|s: &String| {
+            if names.contains(s) {
+                Ok(())
+            } else {
+                Err("Unknown name, press Tab to complete it")
+            }
+        });
+    }
+    interacted(input.interact_text())
+}
+
+// Asks for a number that must be above `min`, or at least `min` when `zero_ok`.
+fn ask_number(prompt: &str, default: Option<f64>, min: f64, zero_ok: bool) -> Result<f64> {
+    let mut input = Input::<f64>::new()
+        .with_prompt(prompt)
+        .validate_with(|v: &f64| {
+            if *v > min || (zero_ok && *v == min) {
+                Ok(())
+            } else if zero_ok {
+                Err(format!("It can't be less than {}", min))
+            } else {
+                Err(format!("It must be more than {}", min))
+            }
+        });
+    if let Some(d) = default {
+        input = input.default(d);
+    }
+    interacted(input.interact_text())
+}
+
+fn ask_date(prompt: &str, default: &str, allow_empty: bool) -> Result<String> {
+    let input = Input::<String>::new()
+        .with_prompt(prompt)
+        .default(default.to_string())
+        .show_default(!default.is_empty())
+        .allow_empty(allow_empty)
+        .validate_with(|s: &String| {
+            if s.is_empty() {
+                Ok(())
+            } else {
+                parse_date(s).map(|_| ())
+            }
+        });
+    interacted(input.interact_text())
+}
+
+/// Asks for the fields of a trade missing from the draft, then shows the trade and how it
+/// changes its position. Returns the trade if the user confirms it.
+pub fn prompt_trade(store: &Store, draft: TradeDraft) -> Result<Option<Trade>> {
+    let r#type = match draft.r#type {
+        Some(t) => t,
+        None => {
+            let i = Select::new()
+                .with_prompt("Type")
+                .items(
+                    &TRADE_TYPES
+                        .iter()
+                        .map(|t| format!("{:?}", t))
+                        .collect::<Vec<_>>(),
+                )
+                .default(0)
+                .interact();
+            TRADE_TYPES[interacted(i)?]
+        }
+    };
+
+    let account = match draft.account {
+        Some(a) => a,
+        None => ask_name("Account", &store.accounts()?, false)?,
+    };
+    let stock = match draft.stock {
+        Some(s) => s,
+        None => {
+            let mut names: Vec<String> = store.load_stocks()?.into_keys().collect();
+            names.sort();
+            ask_name("Stock", &names, true)?
+        }
+    };
+
+    let date = match draft.date {
+        Some(d) => d,
+        None => {
+            let today = chrono::Utc::today().format("%Y/%m/%d").to_string();
+            parse_date(&ask_date("Date", &today, false)?)?
+        }
+    };
+
+    let (units, price, fees, split) = if r#type == TradeType::Split {
+        let split = match draft.split {
+            Some(s) => s,
+            None => ask_number("New units for each old one", None, 0.0, false)?,
+        };
+        (0.0, None, None, split)
+    } else {
+        let units = match draft.units {
+            Some(u) => u,
+            None => ask_number("Units", None, 0.0, false)?,
+        };
+        let price = match draft.price {
+            Some(p) => p,
+            None => {
+                let last = store.last_price(&stock)?.map(|p| p.price);
+                ask_number("Price", last, 0.0, true)?
+            }
+        };
+        let fees = match draft.fees {
+            Some(f) => f,
+            None => ask_number("Fees", Some(0.0), 0.0, true)?,
+        };
+        (units, Some(price), Some(fees), 1.0)
+    };
+
+    let lot = match draft.lot {
+        None if r#type == TradeType::Sell => Some(ask_date(
+            "Lot to sell (empty for the lot method)",
+            "",
+            true,
+        )?)
+        .filter(|l| !l.is_empty()),
+        lot => lot,
+    };
+
+    let mut trade = Trade {
+        account,
+        date,
+        r#type,
+        stock,
+        units,
+        price,
+        fees,
+        split,
+        currency: draft.rate,
+        lot,
+    };
+
+    if trade.currency.is_none() {
+        let traded = store
+            .load_stocks()?
+            .get(&trade.stock)
+            .ok_or_else(|| {
+                format!(
+                    "Unknown stock {}, add it to the stocks file first",
+                    trade.stock
+                )
+            })?
+            .tradedcurrency
+            .to_uppercase();
+        if traded != RATE_CURRENCY {
+            let inferred = store
+                .complete_trade(trade.clone())
+                .map(|t| t.currency)
+                .unwrap_or_else(|e| {
+                    eprintln!("{}", e);
+                    None
+                });
+            let prompt = format!("Rate from {} to {}", traded, RATE_CURRENCY);
+            trade.currency = Some(ask_number(&prompt, inferred, 0.0, false)?);
+        }
+    }
+    let trade = store.complete_trade(trade)?;
+
+    eprintln!();
+    eprintln!("{}", trade);
+    for change in store.position_change(&trade)? {
+        eprintln!("{}", change);
+    }
+    let confirmed = Confirm::new()
+        .with_prompt("Add this trade?")
+        .default(true)
+        .interact();
+    Ok(if interacted(confirmed)? {
+        Some(trade)
+    } else {
+        None
+    })
+}
+
+/// Shows the errors found in an edited file and asks what to do with it.
+pub fn edit_choice(errors: &[String]) -> Result<EditChoice> {
+    eprintln!();
+    for e in errors {
+        eprintln!("{}", e);
+    }
+    let choices = [
+        (EditChoice::Edit, "Edit again"),
+        (EditChoice::Discard, "Discard the changes"),
+        (EditChoice::Save, "Save with the errors"),
+    ];
+    let i = Select::new()
+        .with_prompt(format!("{} errors found", errors.len()))
+        .items(&choices.iter().map(|(_, label)| label).collect::<Vec<_>>())
+        .default(0)
+        .interact();
+    Ok(choices[interacted(i)?].0)
+}
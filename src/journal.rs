@@ -0,0 +1,373 @@
+use std::path::{Path, PathBuf};
+use std::{fmt, fs};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::errors::*;
+use crate::{files, tsv};
+
+/// Directory, in the portfolio one, with the changes made to the trades and stocks files.
+pub const JOURNAL_DIR: &str = "journal";
+const INDEX_FILE: &str = "journal.tsv";
+
+/// A change made to a portfolio file. The lines it removed and added are kept, with the file
+/// as the last change left it, so that it can be undone.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct Change {
+    pub id: u64,
+    #[serde(with = "timestamp")]
+    pub date: DateTime<Utc>,
+    pub file: String,
+    /// What made the change.
+    pub command: String,
+    pub added: usize,
+    pub removed: usize,
+}
+
+#[macro_export]
+macro_rules! fmt_change {
+    () => {
+        "{:>5}\t{:<19}\t{:<12}\t{:<20}\t{:>7}\t{:>7}"
+    };
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            fmt_change!(),
+            self.id,
+            self.date.format("%Y/%m/%d %H:%M:%S"),
+            self.file,
+            self.command,
+            format!("+{}", self.added),
+            format!("-{}", self.removed),
+        )
+    }
+}
+
+mod timestamp {
+    use chrono::{DateTime, TimeZone, Utc};
+    use serde::{self, Deserialize, Deserializer, Serializer};
+
+    const FORMAT: &str = "%Y/%m/%d %H:%M:%S";
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<DateTime<Utc>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        Utc.datetime_from_str(&s, FORMAT)
+            .map_err(serde::de::Error::custom)
+    }
+
+    pub fn serialize<S>(date: &DateTime<Utc>, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(&date.format(FORMAT).to_string())
+    }
+}
+
+fn dir(home_dir: &Path) -> PathBuf {
+    home_dir.join(JOURNAL_DIR)
+}
+
+// The file as the last change to it left it, the one changes are taken back from.
+fn latest_path(home_dir: &Path, file: &str) -> PathBuf {
+    dir(home_dir).join(format!("latest.{}", file))
+}
+
+fn diff_path(home_dir: &Path, id: u64) -> PathBuf {
+    dir(home_dir).join(format!("{}.diff", id))
+}
+
+/// The changes recorded so far, oldest first.
+pub fn changes(home_dir: &Path) -> Result<Vec<Change>> {
+    let path = dir(home_dir).join(INDEX_FILE);
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let mut rdr = tsv::reader()
+        .from_path(path)
+        .chain_err(|| "Cannot open journal file")?;
+
+    tsv::read_rows(&mut rdr, INDEX_FILE)?
+        .into_iter()
+        .map(|(_, r)| r)
+        .collect()
+}
+
+/// The lines removed, starting with '-', and added, starting with '+', by a change, under
+/// headers with the line numbers they are at.
+pub fn diff(home_dir: &Path, id: u64) -> Result<String> {
+    fs::read_to_string(diff_path(home_dir, id))
+        .chain_err(|| format!("Cannot read the changes of {}", id))
+}
+
+/// The contents of the file as the last change to it left it, none if it has no changes.
+pub fn latest(home_dir: &Path, file: &str) -> Result<Option<Vec<u8>>> {
+    let path = latest_path(home_dir, file);
+    if !path.exists() {
+        return Ok(None);
+    }
+    fs::read(path)
+        .map(Some)
+        .chain_err(|| format!("Cannot read the journaled {}", file))
+}
+
+/// The contents of the file before the change: the file as the last change left it, with the
+/// changes made to it from this one on taken back, latest first.
+pub fn before(home_dir: &Path, change: &Change) -> Result<Vec<u8>> {
+    let mut text = latest(home_dir, &change.file)?
+        .ok_or_else(|| format!("Cannot find the journaled {}", change.file))?;
+    let changes = changes(home_dir)?;
+    let later = changes
+        .iter()
+        .rev()
+        .filter(|c| c.file == change.file && c.id >= change.id);
+    for c in later {
+        text = revert(&text, &diff(home_dir, c.id)?)
+            .chain_err(|| format!("Cannot take back change {}", c.id))?;
+    }
+    Ok(text)
+}
+
+/// The ids of the changes an undo reverted, none if the change is not an undo.
+pub fn undone_ids(change: &Change) -> Vec<u64> {
+    change
+        .command
+        .strip_prefix("undo ")
+        .map_or(Vec::new(), |ids| {
+            ids.split(',').filter_map(|id| id.parse().ok()).collect()
+        })
+}
+
+/// Records the change from `before` to `after` of a file, nothing if they are the same.
+/// It is recorded before the file is written, so a change is never lost. A file changed
+/// outside of the journal since its last change gets that change recorded first, as `outside`,
+/// so that changes can always be taken back one after the other.
+pub fn record(
+    home_dir: &Path,
+    file: &str,
+    command: &str,
+    before: &[u8],
+    after: &[u8],
+) -> Result<Option<Change>> {
+    // Storages may quote the rows differently from the text they were given.
+    let before = match latest(home_dir, file)? {
+        Some(latest) if tsv::same_rows(before, &latest) => latest,
+        Some(latest) => {
+            add(home_dir, file, "outside", &latest, before)?;
+            before.to_vec()
+        }
+        None => before.to_vec(),
+    };
+    add(home_dir, file, command, &before, after)
+}
+
+// Adds a change to the journal, with its diff, and keeps the file as it left it.
+fn add(
+    home_dir: &Path,
+    file: &str,
+    command: &str,
+    before: &[u8],
+    after: &[u8],
+) -> Result<Option<Change>> {
+    if before == after {
+        return Ok(None);
+    }
+    let hunks = hunks(before, after);
+    let added = hunks.iter().map(|h| h.added.len()).sum();
+    let removed = hunks.iter().map(|h| h.removed.len()).sum();
+
+    fs::create_dir_all(dir(home_dir)).chain_err(|| "Cannot create the journal directory")?;
+    let mut changes = changes(home_dir)?;
+    let change = Change {
+        id: changes.last().map_or(1, |c| c.id + 1),
+        date: Utc::now(),
+        file: file.to_string(),
+        command: command.to_string(),
+        added,
+        removed,
+    };
+    let text: String = hunks.iter().map(|h| h.to_string()).collect();
+    files::write_atomic(&diff_path(home_dir, change.id), text.as_bytes())?;
+    files::write_atomic(&latest_path(home_dir, file), after)?;
+
+    changes.push(change.clone());
+    let mut wtr = csv::WriterBuilder::new()
+        .delimiter(b'\t')
+        .from_writer(Vec::new());
+    for c in &changes {
+        wtr.serialize(c)
+            .chain_err(|| "Error serializing a change")?;
+    }
+    let index = wtr
+        .into_inner()
+        .chain_err(|| "Error serializing the journal")?;
+    files::write_atomic(&dir(home_dir).join(INDEX_FILE), &index)?;
+    Ok(Some(change))
+}
+
+// Lines a change removed and added together, at the index of the first one in the file before
+// and after the change.
+#[derive(Debug, Default)]
+struct Hunk {
+    old_start: usize,
+    new_start: usize,
+    removed: Vec<Vec<u8>>,
+    added: Vec<Vec<u8>>,
+}
+
+const NO_END_OF_LINE: &str = "\\ No newline at end of file";
+
+// A diff is written as a unified diff without context lines.
+impl fmt::Display for Hunk {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Empty ranges start at the line before them.
+        let range = |start: usize, count: usize| match count {
+            0 => format!("{},0", start),
+            1 => format!("{}", start + 1),
+            _ => format!("{},{}", start + 1, count),
+        };
+        writeln!(
+            f,
+            "@@ -{} +{} @@",
+            range(self.old_start, self.removed.len()),
+            range(self.new_start, self.added.len())
+        )?;
+        let removed = self.removed.iter().map(|l| ('-', l));
+        for (sign, line) in removed.chain(self.added.iter().map(|l| ('+', l))) {
+            let text = String::from_utf8_lossy(line);
+            match text.strip_suffix('\n') {
+                Some(text) => writeln!(f, "{}{}", sign, text)?,
+                None => writeln!(f, "{}{}\n{}", sign, text, NO_END_OF_LINE)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+// The lines of the text, with their end of line.
+fn lines(text: &[u8]) -> Vec<&[u8]> {
+    text.split_inclusive(|c| *c == b'\n').collect()
+}
+
+// The lines removed and added to turn `before` into `after`.
+fn hunks(before: &[u8], after: &[u8]) -> Vec<Hunk> {
+    let (old, new) = (lines(before), lines(after));
+    let (mut i, mut j) = (0, 0);
+    let mut hunks: Vec<Hunk> = Vec::new();
+    let mut open = false;
+    for d in diff::slice(&old, &new) {
+        if !open && !matches!(d, diff::Result::Both(..)) {
+            hunks.push(Hunk {
+                old_start: i,
+                new_start: j,
+                ..Default::default()
+            });
+        }
+        open = true;
+        match d {
+            diff::Result::Left(l) => {
+                hunks.last_mut().unwrap().removed.push(l.to_vec());
+                i += 1;
+            }
+            diff::Result::Right(r) => {
+                hunks.last_mut().unwrap().added.push(r.to_vec());
+                j += 1;
+            }
+            diff::Result::Both(..) => {
+                open = false;
+                i += 1;
+                j += 1;
+            }
+        }
+    }
+    hunks
+}
+
+// The hunks of a diff written by `Hunk`.
+fn parse_hunks(diff: &str) -> Result<Vec<Hunk>> {
+    let mut hunks: Vec<Hunk> = Vec::new();
+    let mut last_added = false;
+    for line in diff.split_terminator('\n') {
+        let hunk = hunks.last_mut();
+        match (line.chars().next(), hunk) {
+            (Some('@'), _) => {
+                // "@@ -a,b +c,d @@", empty ranges start at the line before them.
+                let start = |range: &str| -> Option<usize> {
+                    let (start, count) = range.split_once(',').unwrap_or((range, "1"));
+                    let start: usize = start.parse().ok()?;
+                    Some(if count == "0" {
+                        start
+                    } else {
+                        start.checked_sub(1)?
+                    })
+                };
+                let ranges: Vec<&str> = line.split(' ').collect();
+                let (old_start, new_start) = match &ranges[..] {
+                    ["@@", old, new, "@@"] => (
+                        old.strip_prefix('-').and_then(start),
+                        new.strip_prefix('+').and_then(start),
+                    ),
+                    _ => (None, None),
+                };
+                hunks.push(Hunk {
+                    old_start: old_start.ok_or_else(|| format!("Bad diff header {}", line))?,
+                    new_start: new_start.ok_or_else(|| format!("Bad diff header {}", line))?,
+                    ..Default::default()
+                });
+            }
+            (Some('-'), Some(h)) => {
+                h.removed.push(format!("{}\n", &line[1..]).into_bytes());
+                last_added = false;
+            }
+            (Some('+'), Some(h)) => {
+                h.added.push(format!("{}\n", &line[1..]).into_bytes());
+                last_added = true;
+            }
+            (Some('\\'), Some(h)) => {
+                let lines = if last_added {
+                    &mut h.added
+                } else {
+                    &mut h.removed
+                };
+                if let Some(l) = lines.last_mut() {
+                    l.pop();
+                }
+            }
+            _ => error_chain::bail!("Bad diff line {}", line),
+        }
+    }
+    Ok(hunks)
+}
+
+// The text a change was made to, from the text it left and its diff.
+fn revert(after: &[u8], diff: &str) -> Result<Vec<u8>> {
+    let lines = lines(after);
+    let mut text = Vec::new();
+    let mut next = 0;
+    for h in parse_hunks(diff)? {
+        let end = h.new_start + h.added.len();
+        if h.new_start < next
+            || end > lines.len()
+            || lines[h.new_start..end]
+                .iter()
+                .zip(&h.added)
+                .any(|(l, a)| l != a)
+        {
+            error_chain::bail!("The file doesn't have the lines the change added");
+        }
+        lines[next..h.new_start]
+            .iter()
+            .for_each(|l| text.extend(*l));
+        h.removed.iter().for_each(|l| text.extend(l));
+        next = end;
+    }
+    lines[next..].iter().for_each(|l| text.extend(*l));
+    Ok(text)
+}
@@ -0,0 +1,91 @@
+use chrono::{DateTime, Utc};
+
+use crate::{Trade, TradeType};
+
+/// The trades of the portfolio, in the order of the trades file.
+#[derive(Debug, Clone, Default)]
+pub struct Ledger {
+    trades: Vec<Trade>,
+    lines: Vec<u64>,
+}
+
+/// Which trades a query returns, `None` fields match every trade.
+#[derive(Debug, Clone, Default)]
+pub struct TradeQuery {
+    pub account: Option<String>,
+    pub stock: Option<String>,
+    /// First day included.
+    pub from: Option<DateTime<Utc>>,
+    /// Last day included.
+    pub to: Option<DateTime<Utc>>,
+    pub r#type: Option<TradeType>,
+}
+
+impl TradeQuery {
+    pub fn matches(&self, t: &Trade) -> bool {
+        self.account.as_ref().is_none_or(|a| *a == t.account)
+            && self.stock.as_ref().is_none_or(|s| *s == t.stock)
+            && self.from.is_none_or(|d| t.date >= d)
+            && self.to.is_none_or(|d| t.date <= d)
+            && self.r#type.is_none_or(|ty| ty == t.r#type)
+    }
+}
+
+impl Ledger {
+    /// A ledger of trades read from the given lines of the trades file.
+    pub fn new(trades: Vec<Trade>, lines: Vec<u64>) -> Ledger {
+        Ledger { trades, lines }
+    }
+
+    pub fn len(&self) -> usize {
+        self.trades.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.trades.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Trade> {
+        self.trades.iter()
+    }
+
+    /// The trades with the line of the trades file each was read from.
+    pub fn iter_with_lines(&self) -> impl Iterator<Item = (u64, &Trade)> {
+        self.lines.iter().copied().zip(self.trades.iter())
+    }
+
+    pub fn query<'a>(&'a self, q: &'a TradeQuery) -> impl Iterator<Item = &'a Trade> {
+        self.trades.iter().filter(move |t| q.matches(t))
+    }
+
+    /// The trades made up to the end of the given date, all of them without a date.
+    pub fn until(&self, date: Option<DateTime<Utc>>) -> impl Iterator<Item = &Trade> {
+        self.trades
+            .iter()
+            .filter(move |t| date.is_none_or(|d| t.date <= d))
+    }
+
+    pub fn by_account<'a>(&'a self, account: &'a str) -> impl Iterator<Item = &'a Trade> {
+        self.trades.iter().filter(move |t| t.account == account)
+    }
+
+    pub fn by_stock<'a>(&'a self, stock: &'a str) -> impl Iterator<Item = &'a Trade> {
+        self.trades.iter().filter(move |t| t.stock == stock)
+    }
+
+    pub fn by_type(&self, r#type: TradeType) -> impl Iterator<Item = &Trade> {
+        self.trades.iter().filter(move |t| t.r#type == r#type)
+    }
+
+    /// The trades between the two dates, both included.
+    pub fn between(&self, from: DateTime<Utc>, to: DateTime<Utc>) -> impl Iterator<Item = &Trade> {
+        self.trades
+            .iter()
+            .filter(move |t| t.date >= from && t.date <= to)
+    }
+
+    /// The date of the first trade.
+    pub fn first_date(&self) -> Option<DateTime<Utc>> {
+        self.trades.iter().map(|t| t.date).min()
+    }
+}
@@ -1,35 +1,86 @@
 #![recursion_limit = "1024"]
 
 use std::cell::RefCell;
-use std::io::Write;
-use std::{collections::HashMap, fmt, fs, io, path};
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::{fmt, fs, path};
 
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, Datelike, Utc};
 use itertools::Itertools;
 use log::{info, warn};
 use num_format::{Locale, ToFormattedString};
 use rand::prelude::*;
 use serde::{Deserialize, Serialize};
 use unicode_truncate::UnicodeTruncateStr;
-use yahoo_finance::{history, Interval, Timestamped};
 
 use crate::errors::*;
+use crate::providers::{PriceProvider, ProviderKind};
 
 pub mod args;
+pub mod check;
+pub mod files;
+pub mod journal;
+pub mod ledger;
+pub mod lots;
+pub mod output;
+pub mod performance;
+pub mod prices;
+pub mod providers;
+pub mod storage;
+pub mod tsv;
 
 pub mod errors {
+    #![allow(unexpected_cfgs)]
     error_chain::error_chain! {}
 }
 
 pub const TRADES_FILE: &str = "trades.tsv";
 pub const STOCKS_FILE: &str = "stocks.tsv";
 pub const PRICES_FILE: &str = "prices.tsv";
+pub const ACCOUNTS_FILE: &str = "accounts.tsv";
+pub const JURISDICTIONS_FILE: &str = "jurisdictions.tsv";
+pub const QUOTES_DIR: &str = "quotes";
+pub const CONFIG_FILE: &str = "config.tsv";
+
+/// Columns of the trades file, in the order new files are created with.
+pub const TRADE_COLUMNS: [&str; 10] = [
+    "Account", "Date", "Type", "Stock", "Units", "Price", "Fees", "Split", "Currency", "Lot",
+];
+
+/// Currency amounts are expressed in when the config file doesn't set one.
+pub const DEFAULT_CURRENCY: &str = "USD";
+
+/// Currency the rates of the trades file convert into, whatever the base currency.
+pub const RATE_CURRENCY: &str = "USD";
 
 pub struct Store<'a> {
     pub home_dir: &'a path::Path,
+    /// Base currency all valuations are expressed in.
+    pub currency: String,
+    /// Skips rows that can't be read or refer to unknown stocks, with a warning, instead
+    /// of stopping.
+    pub lenient: bool,
+    // Copies being edited, read in place of the files they replace.
+    edits: RefCell<HashMap<String, path::PathBuf>>,
+    // Held for as long as the store is open.
+    lock: Arc<files::DirLock>,
+    // Where the tables are kept, set by the Storage of the config file.
+    storage: Box<dyn storage::Storage>,
+    // The trades, read the first time they are needed.
+    ledger: RefCell<Option<Rc<ledger::Ledger>>>,
 }
 
-#[derive(Debug, Deserialize, Serialize)]
+/// What to do with an edited file that doesn't pass the checks.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EditChoice {
+    Edit,
+    Discard,
+    Save,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct PriceLine {
     pub ticker: String,
     pub price: f64,
@@ -37,7 +88,7 @@ pub struct PriceLine {
     pub date: DateTime<Utc>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
 pub enum TradeType {
     Buy,
     Sell,
@@ -47,22 +98,28 @@ pub enum TradeType {
     Split,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 #[serde(rename_all = "PascalCase")]
-pub struct Trade<'a> {
-    pub account: &'a str,
+pub struct Trade {
+    pub account: String,
     #[serde(with = "my_date_format")]
     pub date: DateTime<Utc>,
     pub r#type: TradeType,
-    pub stock: &'a str,
+    pub stock: String,
     pub units: f64,
     pub price: Option<f64>,
     pub fees: Option<f64>,
     pub split: f64,
-    pub currency: f64,
+    /// Rate converting the price and fees into US dollars. When empty, the rate of the stock's
+    /// traded currency on the trade date is taken from the prices file. Trades of the ledger
+    /// hold the rate into the base currency instead.
+    pub currency: Option<f64>,
+    /// Acquisition date of the lot to sell, for accounts using specific lot matching.
+    #[serde(default)]
+    pub lot: Option<String>,
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 #[serde(rename_all = "PascalCase")]
 pub struct Stocks {
     pub name: String,
@@ -73,14 +130,45 @@ pub struct Stocks {
     pub ticker: Option<String>,
     pub tradedcurrency: String,
     pub currencyunderlying: String,
+    /// Stocks with the same value here are substantially identical for wash sales.
+    pub identical: Option<String>,
+    pub provider: Option<ProviderKind>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct Account {
+    pub name: String,
+    pub lotmethod: lots::LotMethod,
+    pub jurisdiction: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct Jurisdiction {
+    pub name: String,
+    /// Days a lot must be held for its gains to be long-term.
+    pub longtermdays: i64,
+    /// Days before and after a loss in which a purchase makes it a wash sale, 0 for none.
+    pub washsaledays: Option<i64>,
+}
+
+/// A `Name Value` line of the config file.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Setting {
+    name: String,
+    value: String,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
 pub struct PortLine {
     pub account: String,
     pub ticker: Option<String>,
     pub name: String,
     pub currency: String,
+    /// Currency the price is quoted in.
+    pub traded_currency: String,
     pub asset: String,
     pub group: String,
     pub tags: String,
@@ -88,24 +176,256 @@ pub struct PortLine {
     pub units: f64,
     pub price: f64,
     pub error: String,
-    pub amount_usd: f64,
+    pub amount: f64,
     pub amount_perc: f64,
-    pub cost_usd: f64,
-    pub revenue_usd: f64,
-    pub divs_usd: f64,
-    pub fees_usd: f64,
+    pub cost: f64,
+    pub revenue: f64,
+    pub divs: f64,
+    pub fees: f64,
+    pub basis: f64,
+    /// Cost of the open lots in the traded currency.
+    pub basis_local: f64,
+    pub realized: f64,
+    pub unrealized: f64,
+    #[serde(serialize_with = "my_date_format::serialize")]
     pub last_trade: DateTime<Utc>,
     pub gain: f64,
+    /// The part of the gain due to the price in the traded currency.
+    pub price_gain: f64,
+    /// The part of the gain due to the exchange rate.
+    pub fx_gain: f64,
     pub tax_status: String,
+    pub lt_units: f64,
+    pub st_units: f64,
+    #[serde(serialize_with = "my_date_format::serialize_opt")]
+    pub next_lt: Option<DateTime<Utc>>,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize)]
 pub struct ReportLine {
     pub group: String,
-    pub amount_usd: f64,
+    pub amount: f64,
     pub amount_perc: f64,
 }
 
+/// The part of a sale that closed one lot.
+#[derive(Debug, Clone, Serialize)]
+pub struct GainLine {
+    pub account: String,
+    pub stock: String,
+    #[serde(with = "my_date_format")]
+    pub acquired: DateTime<Utc>,
+    #[serde(with = "my_date_format")]
+    pub sold: DateTime<Utc>,
+    pub units: f64,
+    pub proceeds: f64,
+    pub basis: f64,
+    pub fees: f64,
+    pub wash: f64,
+    pub gain: f64,
+    pub term: String,
+}
+
+#[macro_export]
+macro_rules! fmt_gain {
+    () => {
+        "{:<10}\t{:<25}\t{:<10}\t{:<10}\t{:>10}\t{:>10}\t{:>10}\t{:>8}\t{:>8}\t{:>10}\t{:<2}"
+    };
+}
+
+impl fmt::Display for GainLine {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            fmt_gain!(),
+            self.account.unicode_truncate(10).0,
+            self.stock.unicode_truncate(25).0,
+            self.acquired.format("%Y/%m/%d"),
+            self.sold.format("%Y/%m/%d"),
+            self.units.sep(),
+            self.proceeds.sep(),
+            self.basis.sep(),
+            self.fees.sep(),
+            self.wash.sep(),
+            self.gain.sep(),
+            self.term,
+        )
+    }
+}
+
+/// Time-weighted and money-weighted return of a part of the portfolio over a period.
+#[derive(Debug, Clone, Serialize)]
+pub struct PerformanceLine {
+    pub segment: String,
+    pub period: String,
+    #[serde(with = "my_date_format")]
+    pub from: DateTime<Utc>,
+    #[serde(with = "my_date_format")]
+    pub to: DateTime<Utc>,
+    /// Cumulative return over the period, independent of when money was added or withdrawn.
+    pub twr: Option<f64>,
+    /// Annualized internal rate of return of the money invested.
+    pub irr: Option<f64>,
+}
+
+#[macro_export]
+macro_rules! fmt_performance {
+    () => {
+        "{:<25}\t{:<3}\t{:<10}\t{:<10}\t{:>8}\t{:>8}"
+    };
+}
+
+impl fmt::Display for PerformanceLine {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let perc = |r: Option<f64>| r.map_or("".to_string(), |r| format!("{:.2}", r * 100.0));
+        write!(
+            f,
+            fmt_performance!(),
+            self.segment.unicode_truncate(25).0,
+            self.period,
+            self.from.format("%Y/%m/%d"),
+            self.to.format("%Y/%m/%d"),
+            perc(self.twr),
+            perc(self.irr),
+        )
+    }
+}
+
+/// The prices downloaded for one ticker by a price update, or why they couldn't be.
+#[derive(Debug)]
+pub struct PriceUpdate {
+    pub ticker: String,
+    /// The date prices were requested from, for a backfill.
+    pub from: Option<DateTime<Utc>>,
+    pub prices: Result<Vec<PriceLine>>,
+}
+
+/// Value of the whole portfolio.
+#[derive(Debug, Clone, Serialize)]
+pub struct TotalLine {
+    pub currency: String,
+    pub amount: f64,
+}
+
+impl fmt::Display for TotalLine {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}\t{:<10}", self.currency, self.amount.sep())
+    }
+}
+
+/// Rows of a portfolio file read without errors by `check`.
+#[derive(Debug, Clone, Serialize)]
+pub struct CheckLine {
+    pub file: String,
+    pub rows: usize,
+}
+
+impl fmt::Display for CheckLine {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {} processed correctly.", self.rows, self.file)
+    }
+}
+
+/// A sale with part of its loss disallowed by the wash sale rule.
+#[derive(Debug, Clone, Serialize)]
+pub struct WashSaleLine {
+    pub account: String,
+    #[serde(with = "my_date_format")]
+    pub date: DateTime<Utc>,
+    pub stock: String,
+    pub units: f64,
+    pub wash: f64,
+}
+
+impl From<&lots::Sale> for WashSaleLine {
+    fn from(s: &lots::Sale) -> WashSaleLine {
+        WashSaleLine {
+            account: s.account.clone(),
+            date: s.date,
+            stock: s.stock.clone(),
+            units: s.units,
+            wash: s.wash,
+        }
+    }
+}
+
+impl fmt::Display for WashSaleLine {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Wash sale: {} {} {} {}, {} of loss disallowed.",
+            self.account,
+            self.date.format("%Y/%m/%d"),
+            self.stock,
+            self.units.sep(),
+            self.wash.sep()
+        )
+    }
+}
+
+/// Gains realized by the sales of an account, or of a term.
+#[derive(Debug, Clone, Serialize)]
+pub struct GainTotalLine {
+    pub name: String,
+    pub gain: f64,
+}
+
+#[macro_export]
+macro_rules! fmt_gain_total {
+    () => {
+        "{:<10}\t{:>10}"
+    };
+}
+
+impl fmt::Display for GainTotalLine {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, fmt_gain_total!(), self.name, self.gain.sep())
+    }
+}
+
+/// How many prices a backfill downloaded for a ticker, and from when.
+#[derive(Debug, Clone, Serialize)]
+pub struct BackfillLine {
+    pub ticker: String,
+    pub prices: usize,
+    #[serde(with = "my_date_format")]
+    pub from: DateTime<Utc>,
+}
+
+impl fmt::Display for BackfillLine {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:10}\t{:>10}\t{}",
+            self.ticker,
+            self.prices,
+            self.from.format("%d/%m/%Y")
+        )
+    }
+}
+
+/// Units held in a position before and after a trade.
+#[derive(Debug, Clone, Serialize)]
+pub struct PositionChange {
+    pub account: String,
+    pub stock: String,
+    pub before: f64,
+    pub after: f64,
+}
+
+impl fmt::Display for PositionChange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:<10}\t{:<25}\t{:>10} -> {:>10}",
+            self.account,
+            self.stock,
+            self.before.sep(),
+            self.after.sep()
+        )
+    }
+}
+
 #[macro_export]
 macro_rules! fmt_report {
     () => {
@@ -119,7 +439,7 @@ impl fmt::Display for ReportLine {
             f,
             fmt_report!(),
             self.group.unicode_truncate(15).0,
-            self.amount_usd.sep(),
+            self.amount.sep(),
             (self.amount_perc * 100.0)
         )
     }
@@ -127,7 +447,7 @@ impl fmt::Display for ReportLine {
 
 #[macro_export]
 macro_rules! fmt_portline { () =>
-    {"{:<8}{:>5.1}\t{:<10}\t{:<25}\t{:<5}\t{:<10}\t{:<15}\t{:<10}\t{:<1}\t{:>10}\t{:>10.2}\t{:>10}\t{:>10}\t{:>2}\t{:<2}"};
+    {"{:<8}{:>5.1}\t{:<10}\t{:<25}\t{:<5}\t{:<10}\t{:<15}\t{:<10}\t{:<1}\t{:>10}\t{:>10.2}\t{:>10}\t{:>10}\t{:>10}\t{:>2}\t{:>10}\t{:<10}\t{:<2}"};
 }
 impl PortLine {
     fn from(s: &Stocks) -> PortLine {
@@ -136,41 +456,82 @@ impl PortLine {
             ticker: s.ticker.as_ref().map(|s| s.to_owned()),
             name: s.name.to_owned(),
             currency: s.currencyunderlying.to_owned(),
+            traded_currency: s.tradedcurrency.to_uppercase(),
             asset: s.asset.to_owned(),
             group: s.group.to_owned(),
             tags: s.tags.to_owned(),
             riskyness: s.riskyness.to_owned(),
             units: 0.0,
-            cost_usd: 0.0,
-            revenue_usd: 0.0,
-            divs_usd: 0.0,
-            fees_usd: 0.0,
+            cost: 0.0,
+            revenue: 0.0,
+            divs: 0.0,
+            fees: 0.0,
+            basis: 0.0,
+            basis_local: 0.0,
+            realized: 0.0,
+            unrealized: 0.0,
             last_trade: Utc::now(),
             price: 0.0,
             error: "".to_owned(),
-            amount_usd: 0.0,
+            amount: 0.0,
             amount_perc: 0.0,
             gain: 0.0,
+            price_gain: 0.0,
+            fx_gain: 0.0,
             tax_status: "".to_string(),
+            lt_units: 0.0,
+            st_units: 0.0,
+            next_lt: None,
+        }
+    }
+
+    fn merge(&mut self, o: &PortLine) {
+        self.account = format!("{},{}", self.account, o.account);
+        // Error codes are two letters each, the merged line has each of them once.
+        let codes = |error: &str| error.chars().collect::<Vec<_>>();
+        let mine = codes(&self.error);
+        for code in codes(&o.error).chunks(2) {
+            if !mine.chunks(2).any(|c| c == code) {
+                self.error.extend(code);
+            }
+        }
+        self.units += o.units;
+        self.cost += o.cost;
+        self.revenue += o.revenue;
+        self.divs += o.divs;
+        self.fees += o.fees;
+        self.basis += o.basis;
+        self.basis_local += o.basis_local;
+        self.realized += o.realized;
+        self.fx_gain += o.fx_gain;
+        self.lt_units += o.lt_units;
+        self.st_units += o.st_units;
+        if o.last_trade > self.last_trade {
+            self.last_trade = o.last_trade;
         }
+        self.next_lt = match (self.next_lt, o.next_lt) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
     }
 }
 
 mod my_date_format {
     use chrono::{DateTime, TimeZone, Utc};
+    use serde::de::Unexpected;
     use serde::{self, Deserialize, Deserializer, Serializer};
 
-    const FORMAT_IN: &'static str = "%Y/%m/%d %H:%M:%S";
-    const FORMAT_OUT: &'static str = "%Y/%m/%d";
+    const FORMAT_IN: &str = "%Y/%m/%d %H:%M:%S";
+    const FORMAT_OUT: &str = "%Y/%m/%d";
 
     pub fn deserialize<'de, D>(deserializer: D) -> Result<DateTime<Utc>, D::Error>
     where
         D: Deserializer<'de>,
     {
         let s = String::deserialize(deserializer)?;
-        let ks = s + " 00:00:00";
+        let ks = format!("{} 00:00:00", s);
         Utc.datetime_from_str(&ks, FORMAT_IN)
-            .map_err(serde::de::Error::custom)
+            .map_err(|_| serde::de::Error::invalid_value(Unexpected::Str(&s), &"a YYYY/MM/DD date"))
     }
     pub fn serialize<S>(date: &DateTime<Utc>, serializer: S) -> Result<S::Ok, S::Error>
     where
@@ -179,6 +540,15 @@ mod my_date_format {
         let s = format!("{}", date.format(FORMAT_OUT));
         serializer.serialize_str(&s)
     }
+    pub fn serialize_opt<S>(date: &Option<DateTime<Utc>>, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        match date {
+            Some(d) => serialize(d, serializer),
+            None => serializer.serialize_none(),
+        }
+    }
 }
 impl fmt::Display for TradeType {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -197,6 +567,25 @@ impl fmt::Display for TradeType {
     }
 }
 
+impl FromStr for TradeType {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<TradeType> {
+        match &s.to_lowercase()[..] {
+            "buy" => Ok(TradeType::Buy),
+            "sell" => Ok(TradeType::Sell),
+            "trin" => Ok(TradeType::TrIn),
+            "trout" => Ok(TradeType::TrOut),
+            "div" => Ok(TradeType::Div),
+            "split" => Ok(TradeType::Split),
+            _ => error_chain::bail!(
+                "Unknown trade type {}, use Buy, Sell, TrIn, TrOut, Div or Split",
+                s
+            ),
+        }
+    }
+}
+
 pub trait Separate {
     fn sep(&self) -> String;
 }
@@ -214,7 +603,50 @@ macro_rules! fmt_trade {
     };
 }
 
-impl fmt::Display for Trade<'_> {
+impl Trade {
+    /// Rate converting the trade amounts into the base currency, for trades of the ledger.
+    pub fn rate(&self) -> f64 {
+        self.currency.unwrap_or(1.0)
+    }
+}
+
+/// The fields of a trade known so far, as given on the command line.
+#[derive(Debug, Clone, Default)]
+pub struct TradeDraft {
+    pub r#type: Option<TradeType>,
+    pub account: Option<String>,
+    pub stock: Option<String>,
+    pub units: Option<f64>,
+    pub price: Option<f64>,
+    pub fees: Option<f64>,
+    pub split: Option<f64>,
+    pub date: Option<DateTime<Utc>>,
+    pub rate: Option<f64>,
+    pub lot: Option<String>,
+}
+
+impl TradeDraft {
+    /// The trade, dated today if it has no date. The type, account and stock are required.
+    pub fn into_trade(self) -> Result<Trade> {
+        fn required<T>(v: Option<T>, name: &str) -> Result<T> {
+            v.ok_or_else(|| Error::from(format!("{} is required", name)))
+        }
+        Ok(Trade {
+            r#type: required(self.r#type, "The trade type")?,
+            account: required(self.account, "--account")?,
+            stock: required(self.stock, "--stock")?,
+            date: self.date.unwrap_or_else(|| Utc::today().and_hms(0, 0, 0)),
+            units: self.units.unwrap_or_default(),
+            price: self.price,
+            fees: self.fees,
+            split: self.split.unwrap_or(1.0),
+            currency: self.rate,
+            lot: self.lot,
+        })
+    }
+}
+
+impl fmt::Display for Trade {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(
             f,
@@ -242,6 +674,13 @@ impl fmt::Display for PriceLine {
     }
 }
 
+// Stocks are shown with the columns of the portfolio.
+impl fmt::Display for Stocks {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        PortLine::from(self).fmt(f)
+    }
+}
+
 impl fmt::Display for PortLine {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(
@@ -260,9 +699,13 @@ impl fmt::Display for PortLine {
             self.riskyness.unicode_truncate(1).0,
             self.units.sep(),
             self.price,
-            self.amount_usd.sep(),
+            self.amount.sep(),
             self.gain.sep(),
+            self.fx_gain.sep(),
             self.tax_status,
+            self.st_units.sep(),
+            self.next_lt
+                .map_or("".to_string(), |d| d.format("%Y/%m/%d").to_string()),
             self.error,
         )
     }
@@ -270,171 +713,1022 @@ impl fmt::Display for PortLine {
 
 impl Store<'_> {
     pub fn load_stocks(&self) -> Result<HashMap<String, Stocks>> {
-        let mut rdr = csv::ReaderBuilder::new()
-            .delimiter(b'\t')
-            .flexible(true)
-            .trim(csv::Trim::All)
-            .comment(Some(b'#'))
-            .from_path(self.home_dir.join(STOCKS_FILE))
-            .chain_err(|| "Cannot open stocks file")?;
-
-        rdr.deserialize()
-            .map(|r: std::result::Result<Stocks, csv::Error>| {
-                r.chain_err(|| "Badly formatted csv.")
-            })
-            .map(|r| r.map(|s| (s.name.clone(), s)))
-            .collect::<Result<HashMap<String, Stocks>>>()
+        Ok(self
+            .read_stocks()?
+            .into_iter()
+            .map(|(_, s)| (s.name.clone(), s))
+            .collect())
+    }
+
+    /// The stocks with the line of the stocks file each was read from.
+    pub fn read_stocks(&self) -> Result<Vec<(u64, Stocks)>> {
+        let table = self
+            .read_table(STOCKS_FILE)?
+            .ok_or("Cannot open stocks file")?;
+
+        let mut stocks = Vec::new();
+        for (line, row) in tsv::rows(&table, &self.location(STOCKS_FILE)) {
+            match row {
+                Ok(s) => stocks.push((line, s)),
+                Err(e) => {
+                    self.skip_or_fail(Err(e))?;
+                }
+            }
+        }
+        Ok(stocks)
+    }
+
+    // The accounts file is optional, accounts not listed in it use the defaults.
+    pub fn load_accounts(&self) -> Result<HashMap<String, Account>> {
+        let table = match self.read_table(ACCOUNTS_FILE)? {
+            Some(table) => table,
+            None => return Ok(HashMap::new()),
+        };
+        tsv::rows(&table, &self.location(ACCOUNTS_FILE))
+            .into_iter()
+            .map(|(_, r)| r.map(|a: Account| (a.name.clone(), a)))
+            .collect::<Result<HashMap<String, Account>>>()
+    }
+
+    // The config file is optional, every setting has a default.
+    fn load_config(home_dir: &path::Path) -> Result<HashMap<String, String>> {
+        let path = home_dir.join(CONFIG_FILE);
+        if !path.exists() {
+            return Ok(HashMap::new());
+        }
+        let mut rdr = tsv::reader()
+            .from_path(path)
+            .chain_err(|| "Cannot open config file")?;
+
+        tsv::read_rows(&mut rdr, CONFIG_FILE)?
+            .into_iter()
+            .map(|(_, r)| r.map(|s: Setting| (s.name.to_lowercase(), s.value)))
+            .collect::<Result<HashMap<String, String>>>()
+    }
+
+    // The jurisdictions file is optional, the default holding period is one year.
+    pub fn load_jurisdictions(&self) -> Result<HashMap<String, Jurisdiction>> {
+        let table = match self.read_table(JURISDICTIONS_FILE)? {
+            Some(table) => table,
+            None => return Ok(HashMap::new()),
+        };
+        tsv::rows(&table, &self.location(JURISDICTIONS_FILE))
+            .into_iter()
+            .map(|(_, r)| r.map(|j: Jurisdiction| (j.name.clone(), j)))
+            .collect::<Result<HashMap<String, Jurisdiction>>>()
+    }
+
+    /// All the prices ever downloaded, by ticker and date.
+    pub fn load_price_history(&self) -> Result<prices::PriceHistory> {
+        let table = self
+            .read_table(PRICES_FILE)?
+            .ok_or("Cannot open prices file.\n Have you run 'lupo update-prices'?")?;
+
+        let location = self.location(PRICES_FILE);
+        let mut history = prices::PriceHistory::default();
+        for (line, row) in tsv::rows(&table, &location) {
+            let pl: PriceLine = match row {
+                Ok(pl) => pl,
+                Err(e) => {
+                    self.skip_or_fail(Err(e))?;
+                    continue;
+                }
+            };
+            if !pl.price.is_finite() {
+                let nan = format!(
+                    "{}:{} column price: '{}' is not a number",
+                    location, line, pl.price
+                );
+                self.skip_or_fail(Err(nan.into()))?;
+                continue;
+            }
+            history.insert(&pl);
+        }
+        Ok(history)
     }
 
+    /// The latest price of each ticker.
     pub fn load_prices(&self) -> Result<HashMap<String, PriceLine>> {
-        let mut rdr = csv::ReaderBuilder::new()
-            .delimiter(b'\t')
-            .flexible(true)
-            .trim(csv::Trim::All)
-            .comment(Some(b'#'))
-            .from_path(self.home_dir.join(PRICES_FILE))
-            .chain_err(|| "Cannot open prices file.\n Have you run 'lupo update-prices'?")?;
-
-        rdr.deserialize()
-            .map(|r: std::result::Result<PriceLine, csv::Error>| {
-                r.chain_err(|| "Badly formatted csv.")
-            })
-            .map(|r| r.map(|s| (s.ticker.clone(), s)))
-            .collect::<Result<HashMap<String, PriceLine>>>()
+        let history = self.load_price_history()?;
+        Ok(history
+            .tickers()
+            .filter_map(|t| history.latest(t))
+            .map(|pl| (pl.ticker.clone(), pl))
+            .collect())
+    }
+
+    /// The price of the ticker on the date, or the closest one before it.
+    pub fn price_at(&self, ticker: &str, date: DateTime<Utc>) -> Result<Option<PriceLine>> {
+        Ok(self.load_price_history()?.at(ticker, date))
     }
-    pub fn write_prices(&self, lines: Vec<PriceLine>) -> Result<()> {
+
+    /// Appends to the prices file the prices that are not in it already.
+    pub fn append_prices(&self, lines: Vec<PriceLine>) -> Result<()> {
+        self.check_writable()?;
+        self.invalidate_ledger();
+        let history = if self.storage.exists(PRICES_FILE)? {
+            self.load_price_history()?
+        } else {
+            prices::PriceHistory::default()
+        };
+
         let mut wtr = csv::WriterBuilder::new()
             .delimiter(b'\t')
             .quote_style(csv::QuoteStyle::NonNumeric)
-            .from_path(self.home_dir.join(PRICES_FILE))
-            .chain_err(|| "Can't open price file")?;
-
-        for pl in &lines {
+            .from_writer(Vec::new());
+        for pl in lines.iter().filter(|pl| !history.contains(pl)) {
             wtr.serialize(pl)
                 .chain_err(|| "Error serializing one price")?;
         }
-        wtr.flush().chain_err(|| "Error flushing the stocks file")
+        let text = wtr
+            .into_inner()
+            .chain_err(|| "Error serializing the prices")?;
+        if text.is_empty() {
+            return Ok(());
+        }
+        self.storage.append(PRICES_FILE, &text)
     }
 
-    fn trades_fold<R, F>(&self, init: &mut R, f: F) -> Result<()>
-    where
-        F: Fn(&mut R, Trade) -> (),
-    {
-        let mut rdr = csv::ReaderBuilder::new()
-            .delimiter(b'\t')
-            .flexible(true)
-            .trim(csv::Trim::All)
-            .comment(Some(b'#'))
-            .from_path(self.home_dir.join(TRADES_FILE))
-            .chain_err(|| "Cannot open trades file")?;
-
-        let mut raw_record = csv::StringRecord::new();
-        let headers = rdr.headers().chain_err(|| "Can't get headers?")?.clone();
-
-        while rdr
-            .read_record(&mut raw_record)
-            .chain_err(|| "Csv not well formed")?
-        {
-            let record: Trade = raw_record
-                .deserialize(Some(&headers))
-                .chain_err(|| "Csv not well formed")?;
-            f(init, record);
+    /// The trades of the portfolio, read once and shared by every query on this store.
+    pub fn ledger(&self) -> Result<Rc<ledger::Ledger>> {
+        if let Some(l) = self.ledger.borrow().as_ref() {
+            return Ok(l.clone());
         }
-        Ok(())
+        let l = Rc::new(self.load_ledger()?);
+        *self.ledger.borrow_mut() = Some(l.clone());
+        Ok(l)
     }
 
-    pub fn trades(&self, name_substring: Option<String>) -> Result<()> {
-        let s = name_substring.unwrap_or_default().to_lowercase();
-        let mut k = ();
-        let f = |_: &mut _, t: Trade| {
-            if t.stock.to_lowercase().contains(&s) {
-                println!("{}", t)
-            }
+    // The trades depend on the prices for their exchange rates, changing either re-reads them.
+    fn invalidate_ledger(&self) {
+        *self.ledger.borrow_mut() = None;
+    }
+
+    fn load_ledger(&self) -> Result<ledger::Ledger> {
+        let mut stocks = None;
+        let mut history = None;
+        let mut trades = Vec::new();
+        let mut lines = Vec::new();
+
+        // In lenient mode the trades the lots can't take, like sales of more units than are
+        // held, are skipped here, so that every report leaves out the same trades.
+        let mut book = if self.skips_bad_rows() {
+            let book_stocks = self.load_stocks()?;
+            let book = lots::LotBook::new(
+                &self.load_accounts()?,
+                &self.load_jurisdictions()?,
+                &book_stocks,
+            );
+            stocks = Some(book_stocks);
+            Some(book)
+        } else {
+            None
         };
-        self.trades_fold(&mut k, f)?;
-        Ok(())
+
+        for (line, mut record) in self.read_trades()? {
+            // Stocks are only loaded if needed, prices if some trade has no exchange rate or the
+            // base currency is not the dollar its rate converts into.
+            let needs_rate = record.currency.is_none() || self.currency != RATE_CURRENCY;
+            if (self.skips_bad_rows() || needs_rate) && stocks.is_none() {
+                stocks = Some(self.load_stocks()?);
+            }
+            if self.skips_bad_rows() {
+                let known = self.check_trade_stocks(line, &record, stocks.as_ref().unwrap());
+                if self.skip_or_fail(known)? {
+                    continue;
+                }
+            }
+            if needs_rate {
+                if history.is_none() {
+                    history = Some(if self.storage.exists(PRICES_FILE)? {
+                        self.load_price_history()?
+                    } else {
+                        prices::PriceHistory::default()
+                    });
+                }
+                let rate = self
+                    .base_trade_rate(&record, stocks.as_ref().unwrap(), history.as_ref().unwrap())
+                    .chain_err(|| {
+                        let location = self.location(TRADES_FILE);
+                        format!("{}:{}: Trade without a rate", location, line)
+                    });
+                if self.skip_or_fail(rate.map(|r| record.currency = Some(r)))? {
+                    continue;
+                }
+            }
+            if let Some(book) = book.as_mut() {
+                let location = self.location(TRADES_FILE);
+                let applied = book
+                    .apply(&record)
+                    .chain_err(|| format!("{}:{}: Can't match the trade to lots", location, line));
+                if self.skip_or_fail(applied)? {
+                    continue;
+                }
+            }
+            lines.push(line);
+            trades.push(record);
+        }
+        Ok(ledger::Ledger::new(trades, lines))
     }
 
-    pub fn stocks(&self, name_substring: Option<String>) -> Result<()> {
-        let s = name_substring.unwrap_or_default().to_lowercase();
-        let stocks = self.load_stocks()?;
+    // Lenient mode is off while an edited copy is checked, so that its errors are all found
+    // instead of being skipped.
+    fn skips_bad_rows(&self) -> bool {
+        self.lenient && self.edits.borrow().is_empty()
+    }
 
-        stocks
-            .values()
-            .map(|st| PortLine::from(st))
-            .filter(|st| st.name.to_lowercase().contains(&s))
-            .for_each(|st| println!("{}", st));
+    // In lenient mode a failed row is reported and skipped, returning true, otherwise
+    // it stops the command.
+    fn skip_or_fail(&self, r: Result<()>) -> Result<bool> {
+        match r {
+            Ok(()) => Ok(false),
+            Err(e) if self.skips_bad_rows() => {
+                warn!("Skipped {}", e.iter().join(": "));
+                Ok(true)
+            }
+            Err(e) => Err(e),
+        }
+    }
 
+    // The stock of the trade and the cash of its account must be in the stocks file.
+    fn check_trade_stocks(
+        &self,
+        line: u64,
+        t: &Trade,
+        stocks: &HashMap<String, Stocks>,
+    ) -> Result<()> {
+        let cash = format!("Cash{}", t.account);
+        if !stocks.contains_key(&t.stock) {
+            let location = self.location(TRADES_FILE);
+            error_chain::bail!("{}:{}: Unknown stock {}", location, line, t.stock);
+        }
+        if !t.stock.contains("Cash") && !stocks.contains_key(&cash) {
+            error_chain::bail!(
+                "{}:{}: No {} stock for the cash of account {}",
+                self.location(TRADES_FILE),
+                line,
+                cash,
+                t.account
+            );
+        }
         Ok(())
     }
 
-    pub fn check(&self) -> Result<(usize, usize)> {
-        let stocks = self.load_stocks()?;
-        let cs = stocks.iter().count();
+    /// The trades as written in the trades file, with the line each was read from.
+    /// Missing exchange rates are left empty.
+    pub fn read_trades(&self) -> Result<Vec<(u64, Trade)>> {
+        let table = self
+            .read_table(TRADES_FILE)?
+            .ok_or("Cannot open trades file")?;
+
+        let location = self.location(TRADES_FILE);
+        let mut trades = Vec::new();
+        for (line, row) in tsv::rows(&table, &location) {
+            let record: Trade = match row {
+                Ok(t) => t,
+                Err(e) => {
+                    self.skip_or_fail(Err(e))?;
+                    continue;
+                }
+            };
 
-        let f = |c: &mut usize, _: Trade| *c = *c + 1; // if you remove Trade from this line it doesn't work???
-        let mut ct = 0;
-        self.trades_fold(&mut ct, f)?;
+            // Parsing accepts NaN and inf, which would spread to every total.
+            let numbers = [
+                ("Units", Some(record.units)),
+                ("Split", Some(record.split)),
+                ("Price", record.price),
+                ("Fees", record.fees),
+                ("Currency", record.currency),
+            ];
+            let invalid = numbers
+                .iter()
+                .find(|(_, n)| n.is_some_and(|n| !n.is_finite()));
+            if let Some((column, Some(n))) = invalid {
+                let nan = format!(
+                    "{}:{} column {}: '{}' is not a number",
+                    location, line, column, n
+                );
+                self.skip_or_fail(Err(nan.into()))?;
+                continue;
+            }
+            trades.push((line, record));
+        }
+        Ok(trades)
+    }
 
-        Ok((ct, cs))
+    // The rate from the currency the stock is traded in to dollars on the day of the trade.
+    fn trade_rate(
+        &self,
+        t: &Trade,
+        stocks: &HashMap<String, Stocks>,
+        history: &prices::PriceHistory,
+    ) -> Result<f64> {
+        let stock = stocks
+            .get(&t.stock)
+            .ok_or_else(|| Error::from(format!("Unknown stock {} in trades file", t.stock)))?;
+        let currency = stock.tradedcurrency.to_uppercase();
+        history
+            .rate(&currency, RATE_CURRENCY, Some(t.date))
+            .map(|r| r.price)
+            .ok_or_else(|| {
+                Error::from(format!(
+                    "No {} rate on {} for the trade of {}, fill its Currency column or add the rate to the prices file",
+                    prices::fx_ticker(&currency, RATE_CURRENCY),
+                    t.date.format("%Y/%m/%d"),
+                    t.stock
+                ))
+            })
     }
 
-    pub fn report(
+    // The rate of a trade into the base currency: its rate into dollars, taken from the prices
+    // file when empty, times the rate of the dollar on the day of the trade. Stocks traded in
+    // the base currency need no rate.
+    fn base_trade_rate(
         &self,
-        report_type: args::ReportType,
-    ) -> Result<impl Iterator<Item = ReportLine> + '_> {
-        let port = self.port(false, false)?;
-        let f = match report_type {
-            args::ReportType::Asset => |l: PortLine| l.asset,
-            args::ReportType::Currency => |l: PortLine| l.currency,
-            args::ReportType::Group => |l: PortLine| l.group,
-            args::ReportType::Riskyness => |l: PortLine| l.riskyness,
-            args::ReportType::Tags => |l: PortLine| l.tags,
+        t: &Trade,
+        stocks: &HashMap<String, Stocks>,
+        history: &prices::PriceHistory,
+    ) -> Result<f64> {
+        let traded = stocks
+            .get(&t.stock)
+            .map(|s| s.tradedcurrency.to_uppercase());
+        if traded.as_ref() == Some(&self.currency) {
+            return Ok(1.0);
+        }
+        let rate = match t.currency {
+            Some(rate) => rate,
+            None => self.trade_rate(t, stocks, history)?,
         };
-        let groups = port
-            .iter()
-            .map(|l| (f((*l).clone()), l.clone()))
+        Ok(rate * self.dollar_rate(t, history)?)
+    }
+
+    // The rate from dollars to the base currency on the day of the trade.
+    fn dollar_rate(&self, t: &Trade, history: &prices::PriceHistory) -> Result<f64> {
+        history
+            .rate(RATE_CURRENCY, &self.currency, Some(t.date))
+            .map(|r| r.price)
+            .ok_or_else(|| {
+                Error::from(format!(
+                    "No {} rate on {} for the trade of {}, add the rate to the prices file",
+                    prices::fx_ticker(RATE_CURRENCY, &self.currency),
+                    t.date.format("%Y/%m/%d"),
+                    t.stock
+                ))
+            })
+    }
+
+    // The amount moved by a trade in the cash of its account, counted in the currency of that
+    // cash. Cash in the base currency, or with no rate, is moved by the amount itself.
+    fn cash_amount(
+        &self,
+        t: &Trade,
+        amount: f64,
+        stocks: &HashMap<String, Stocks>,
+        history: &prices::PriceHistory,
+    ) -> f64 {
+        let rate = stocks
+            .get(&format!("Cash{}", t.account))
+            .and_then(|s| {
+                history.rate(
+                    &s.tradedcurrency.to_uppercase(),
+                    &self.currency,
+                    Some(t.date),
+                )
+            })
+            .map_or(1.0, |r| r.price);
+        amount / rate
+    }
+
+    /// Checks that a trade refers to a known stock and has the fields its type needs.
+    pub fn validate_trade(&self, t: &Trade, stocks: &HashMap<String, Stocks>) -> Result<()> {
+        if !stocks.contains_key(&t.stock) {
+            error_chain::bail!("Unknown stock {}, add it to the stocks file first", t.stock);
+        }
+        if t.account.is_empty() {
+            error_chain::bail!("The trade has no account");
+        }
+        let text = [Some(&t.account), Some(&t.stock), t.lot.as_ref()]
+            .iter()
+            .flatten()
+            .any(|s| s.contains(['\t', '\n']));
+        if text {
+            error_chain::bail!("Accounts, stocks and lots can't contain tabs or new lines");
+        }
+
+        match t.r#type {
+            TradeType::Split if t.split <= 0.0 => {
+                error_chain::bail!("A split needs a positive split ratio, not {}", t.split)
+            }
+            TradeType::Split => (),
+            _ if t.units <= 0.0 => {
+                error_chain::bail!("A {:?} needs a positive number of units", t.r#type)
+            }
+            _ if t.price.is_none_or(|p| p < 0.0) => {
+                error_chain::bail!("A {:?} needs a price of zero or more", t.r#type)
+            }
+            _ => (),
+        }
+        if t.fees.is_some_and(|f| f < 0.0) {
+            error_chain::bail!("Fees can't be negative");
+        }
+        if t.currency.is_some_and(|c| c <= 0.0) {
+            error_chain::bail!("The exchange rate must be positive");
+        }
+        if let Some(lot) = &t.lot {
+            args::parse_date(lot).map_err(|e| Error::from(format!("Invalid lot: {}", e)))?;
+        }
+        Ok(())
+    }
+
+    /// Validates a trade and fills in its rate to dollars on its date if it has none.
+    pub fn complete_trade(&self, mut t: Trade) -> Result<Trade> {
+        let stocks = self.load_stocks()?;
+        self.validate_trade(&t, &stocks)?;
+
+        if t.currency.is_none() {
+            let traded = stocks[&t.stock].tradedcurrency.to_uppercase();
+            t.currency = Some(if traded == RATE_CURRENCY {
+                1.0
+            } else {
+                self.trade_rate(&t, &stocks, &self.load_price_history()?)?
+            });
+        }
+        Ok(t)
+    }
+
+    /// The accounts traded in, sorted by name.
+    pub fn accounts(&self) -> Result<Vec<String>> {
+        let ledger = self.ledger()?;
+        Ok(ledger
+            .iter()
+            .map(|t| t.account.clone())
+            .unique()
+            .sorted()
+            .collect())
+    }
+
+    /// The latest price of a stock, if it has a ticker with prices.
+    pub fn last_price(&self, stock: &str) -> Result<Option<PriceLine>> {
+        if !self.storage.exists(PRICES_FILE)? {
+            return Ok(None);
+        }
+        let stocks = self.load_stocks()?;
+        let ticker = match stocks.get(stock).and_then(|s| s.ticker.as_ref()) {
+            Some(ticker) => ticker,
+            None => return Ok(None),
+        };
+        Ok(self.load_price_history()?.price(ticker, None))
+    }
+
+    /// How a trade would change the units of its position and the cash of its account,
+    /// counted as the portfolio does.
+    pub fn position_change(&self, t: &Trade) -> Result<Vec<PositionChange>> {
+        let cash = format!("Cash{}", t.account);
+        let mut keys = vec![t.stock.clone()];
+        if !t.stock.contains("Cash") {
+            keys.push(cash.clone());
+        }
+        let stocks = self.load_stocks()?;
+        let history = if self.storage.exists(PRICES_FILE)? {
+            self.load_price_history()?
+        } else {
+            prices::PriceHistory::default()
+        };
+        // The rate of the new trade is into dollars, the ones of the ledger into the base
+        // currency.
+        let mut t = t.clone();
+        t.currency = Some(self.base_trade_rate(&t, &stocks, &history)?);
+
+        let apply = |units: &mut [f64], x: &Trade| {
+            if x.account != t.account {
+                return;
+            }
+            let amt = x.units * x.price.unwrap_or_default() * x.rate();
+            let amt = self.cash_amount(x, amt, &stocks, &history);
+            for (k, u) in keys.iter().zip(units.iter_mut()) {
+                if *k == x.stock {
+                    match x.r#type {
+                        TradeType::Split => *u *= x.split,
+                        TradeType::Buy | TradeType::TrIn => *u += x.units,
+                        TradeType::Sell | TradeType::TrOut => *u -= x.units,
+                        TradeType::Div => (),
+                    }
+                } else if *k == cash && !x.stock.contains("Cash") {
+                    match x.r#type {
+                        TradeType::Buy => *u -= amt,
+                        TradeType::Sell | TradeType::Div => *u += amt,
+                        _ => (),
+                    }
+                }
+            }
+        };
+
+        let mut before = vec![0.0; keys.len()];
+        self.ledger()?.iter().for_each(|x| apply(&mut before, x));
+        let mut after = before.clone();
+        apply(&mut after, &t);
+
+        Ok(keys
+            .into_iter()
+            .zip(before.into_iter().zip(after))
+            .map(|(stock, (before, after))| PositionChange {
+                account: t.account.clone(),
+                stock,
+                before,
+                after,
+            })
+            .collect())
+    }
+
+    /// Adds a trade to the trades file in date order, with the exchange rate of its date if it
+    /// has none.
+    pub fn add_trade(&self, t: Trade) -> Result<Trade> {
+        self.check_writable()?;
+        let t = self.complete_trade(t)?;
+
+        let headers = self
+            .storage
+            .read(TRADES_FILE)?
+            .ok_or("Cannot open trades file")?
+            .headers;
+
+        let num = |v: Option<f64>| v.map_or("".to_string(), |v| v.to_string());
+        let fields = [
+            ("Account", t.account.clone()),
+            ("Date", t.date.format("%Y/%m/%d").to_string()),
+            ("Type", format!("{:?}", t.r#type)),
+            ("Stock", t.stock.clone()),
+            ("Units", t.units.to_string()),
+            ("Price", num(t.price)),
+            ("Fees", num(t.fees)),
+            ("Split", t.split.to_string()),
+            ("Currency", num(t.currency)),
+            ("Lot", t.lot.clone().unwrap_or_default()),
+        ];
+        // The values go in the columns of the file, whatever their order.
+        if let Some((name, _)) = fields
+            .iter()
+            .find(|(n, v)| !v.is_empty() && !headers.iter().any(|h| h.eq_ignore_ascii_case(n)))
+        {
+            error_chain::bail!("The trades file has no {} column", name);
+        }
+        let row: csv::StringRecord = headers
+            .iter()
+            .map(|h| {
+                fields
+                    .iter()
+                    .find(|(n, _)| h.eq_ignore_ascii_case(n))
+                    .map_or("", |(_, v)| &v[..])
+            })
+            .collect();
+
+        // A trade goes after the last one made on its day or before, so that a backdated trade
+        // keeps the file in date order. The others are added at the end. Lines are the ones of
+        // the text, whatever the storage numbers its rows with.
+        let before = self.storage.export(TRADES_FILE)?.unwrap_or_default();
+        let trades: Vec<(u64, Trade)> = tsv::rows(&tsv::parse(&before, TRADES_FILE)?, TRADES_FILE)
+            .into_iter()
+            .filter_map(|(line, row)| row.ok().map(|x| (line, x)))
+            .collect();
+        let mut after = before.clone();
+        if trades.iter().any(|(_, x)| x.date > t.date) {
+            let line = trades
+                .iter()
+                .filter(|(_, x)| x.date <= t.date)
+                .map(|(line, _)| *line)
+                .max()
+                .unwrap_or_else(|| trades.iter().map(|(line, _)| *line).min().unwrap() - 1);
+            let text = tsv::render_records(std::iter::once(&row))?;
+            let mut lines: Vec<&[u8]> = before.split(|c| *c == b'\n').collect();
+            lines.insert(line as usize, text.strip_suffix(b"\n").unwrap_or(&text));
+            after = lines.join(&b'\n');
+        } else {
+            if after.last().is_some_and(|c| *c != b'\n') {
+                after.push(b'\n');
+            }
+            after.extend(tsv::render_records(std::iter::once(&row))?);
+        }
+        journal::record(self.home_dir, TRADES_FILE, "add", &before, &after)?;
+        self.storage.import(TRADES_FILE, &after)?;
+
+        self.invalidate_ledger();
+        Ok(t)
+    }
+
+    /// Open lots and realized sales for all accounts, matched with each account's lot method.
+    pub fn lots(&self) -> Result<lots::LotBook> {
+        self.lots_as_of(None)
+    }
+
+    /// The lots as they were at the end of the given date.
+    pub fn lots_as_of(&self, as_of: Option<DateTime<Utc>>) -> Result<lots::LotBook> {
+        let mut book = lots::LotBook::new(
+            &self.load_accounts()?,
+            &self.load_jurisdictions()?,
+            &self.load_stocks()?,
+        );
+        // Trades lenient mode skips are already out of the ledger.
+        let ledger = self.ledger()?;
+        for (line, t) in ledger.iter_with_lines() {
+            if as_of.is_none_or(|d| t.date <= d) {
+                let location = self.location(TRADES_FILE);
+                book.apply(t).chain_err(|| {
+                    format!("{}:{}: Can't match the trade to lots", location, line)
+                })?;
+            }
+        }
+        book.dispose_transfers(None);
+        Ok(book)
+    }
+
+    /// Sales whose loss is at least partially disallowed by a wash sale.
+    pub fn wash_sales(&self) -> Result<Vec<lots::Sale>> {
+        let book = self.lots()?;
+        Ok(book.sales.into_iter().filter(|s| s.wash > 0.0).collect())
+    }
+
+    /// One line for each lot closed by a `Sell` during the year.
+    pub fn gains(&self, year: i32) -> Result<Vec<GainLine>> {
+        let book = self.lots()?;
+        let mut v = Vec::new();
+
+        for s in book.sales.iter().filter(|s| s.date.year() == year) {
+            let period = chrono::Duration::days(book.long_term_days(&s.account));
+
+            // Proceeds, fees and disallowed losses are split among the lots in proportion
+            // to their units, so that the lines add up to the sale.
+            let matched: f64 = s.lots.iter().map(|m| m.units).sum();
+            for m in &s.lots {
+                let frac = m.units / matched;
+                let proceeds = s.proceeds * frac;
+                let fees = s.fees * frac;
+                let wash = s.wash * frac;
+                v.push(GainLine {
+                    account: s.account.clone(),
+                    stock: s.stock.clone(),
+                    acquired: m.acquired,
+                    sold: s.date,
+                    units: m.units,
+                    proceeds,
+                    basis: m.basis,
+                    fees,
+                    wash,
+                    gain: proceeds - fees - m.basis + wash,
+                    term: if s.date - m.acquired > period {
+                        "LT".to_string()
+                    } else {
+                        "ST".to_string()
+                    },
+                });
+            }
+        }
+        Ok(v)
+    }
+
+    /// The trades of stocks with a name containing the string, in the order of the file.
+    pub fn trades(&self, name_substring: Option<String>) -> Result<Vec<Trade>> {
+        let s = name_substring.unwrap_or_default().to_lowercase();
+        Ok(self
+            .ledger()?
+            .iter()
+            .filter(|t| t.stock.to_lowercase().contains(&s))
+            .cloned()
+            .collect())
+    }
+
+    /// The stocks with a name containing the string, sorted by name.
+    pub fn stocks(&self, name_substring: Option<String>) -> Result<Vec<Stocks>> {
+        let s = name_substring.unwrap_or_default().to_lowercase();
+        let stocks = self.load_stocks()?;
+
+        Ok(stocks
+            .into_values()
+            .filter(|st| st.name.to_lowercase().contains(&s))
+            .sorted_by(|a, b| a.name.cmp(&b.name))
+            .collect())
+    }
+
+    pub fn check(&self) -> Result<(usize, usize)> {
+        let stocks = self.load_stocks()?;
+        let cs = stocks.len();
+
+        let ct = self.ledger()?.len();
+
+        Ok((ct, cs))
+    }
+
+    /// Problems in the stocks and trades files the csv parsing doesn't catch, by file and line.
+    pub fn check_errors(&self) -> Result<Vec<check::CheckError>> {
+        let stocks = self.read_stocks()?;
+        let trades = self.read_trades()?;
+        let history = if self.storage.exists(PRICES_FILE)? {
+            self.load_price_history()?
+        } else {
+            prices::PriceHistory::default()
+        };
+
+        let mut errors = check::check_stocks(&stocks, &trades, &history);
+        let stocks: HashMap<String, Stocks> = stocks
+            .into_iter()
+            .map(|(_, s)| (s.name.clone(), s))
+            .collect();
+        errors.extend(check::check_trades(&stocks, &trades));
+
+        // Trades without an exchange rate take it from the prices file, and all of them the
+        // rate from dollars to the base currency.
+        for (line, t) in trades.iter() {
+            if !stocks.contains_key(&t.stock) {
+                continue;
+            }
+            if let Err(e) = self.base_trade_rate(t, &stocks, &history) {
+                errors.push(check::CheckError {
+                    file: TRADES_FILE.to_string(),
+                    line: *line,
+                    message: e.to_string(),
+                });
+            }
+        }
+        for e in errors.iter_mut() {
+            e.file = self.location(&e.file);
+        }
+        errors.sort_by(|a, b| a.file.cmp(&b.file).then(a.line.cmp(&b.line)));
+        Ok(errors)
+    }
+
+    pub fn report(
+        &self,
+        report_type: args::ReportType,
+        aggregate: bool,
+        as_of: Option<DateTime<Utc>>,
+    ) -> Result<impl Iterator<Item = ReportLine> + '_> {
+        let port = self.port(false, false, aggregate, as_of)?;
+        let f = match report_type {
+            args::ReportType::Account => |l: PortLine| l.account,
+            args::ReportType::Asset => |l: PortLine| l.asset,
+            args::ReportType::Currency => |l: PortLine| l.currency,
+            args::ReportType::Group => |l: PortLine| l.group,
+            args::ReportType::Riskyness => |l: PortLine| l.riskyness,
+            args::ReportType::Tags => |l: PortLine| l.tags,
+        };
+        let groups = port
+            .iter()
+            .map(|l| (f((*l).clone()), l.clone()))
             .into_group_map();
         let rll = groups.into_iter().map(|(k, v)| ReportLine {
             group: k.clone(),
-            amount_usd: v.iter().fold(0.0, |sum, l| sum + l.amount_usd),
+            amount: v.iter().fold(0.0, |sum, l| sum + l.amount),
             amount_perc: v.iter().fold(0.0, |sum, l| sum + l.amount_perc),
         });
         Ok(rll)
     }
 
-    pub fn total(&self) -> Result<f64> {
-        let port = self.port(false, false)?;
-        Ok(port.iter().fold(0.0, |sum, pl| sum + pl.amount_usd))
+    pub fn total(&self, as_of: Option<DateTime<Utc>>) -> Result<f64> {
+        let port = self.port(false, false, false, as_of)?;
+        Ok(port.iter().fold(0.0, |sum, pl| sum + pl.amount))
     }
 
-    pub fn port(&self, all: bool, separate_cash: bool) -> Result<Vec<PortLine>> {
+    /// Returns of the portfolio, of each account and of each group for the standard periods
+    /// ending on the given date. Positions are valued at the end of each month and the returns
+    /// within a month are approximated with the Modified Dietz method.
+    pub fn performance(&self, as_of: Option<DateTime<Utc>>) -> Result<Vec<PerformanceLine>> {
+        use performance::Segment;
+
+        let end = prices::day(as_of.unwrap_or_else(Utc::now));
+        let stocks = self.load_stocks()?;
+
+        let flows: Vec<performance::Flow> = self
+            .ledger()?
+            .until(Some(end))
+            .flat_map(performance::flows)
+            .collect();
+
+        let inception = match flows.iter().map(|f| f.date).min() {
+            Some(d) => d - chrono::Duration::days(1),
+            None => return Ok(Vec::new()),
+        };
+        // A 29th of February becomes the 28th in a year that is not a leap year.
+        let years_ago = |n: i32| {
+            let y = end.year() - n;
+            end.with_year(y)
+                .or_else(|| (end - chrono::Duration::days(1)).with_year(y))
+                .unwrap()
+        };
+        // Calendar periods start at inception for a younger portfolio, trailing ones are skipped.
+        let periods: Vec<(&str, DateTime<Utc>)> = vec![
+            (
+                "MTD",
+                (end - chrono::Duration::days(end.day() as i64)).max(inception),
+            ),
+            (
+                "YTD",
+                (end - chrono::Duration::days(end.ordinal() as i64)).max(inception),
+            ),
+            ("1Y", years_ago(1)),
+            ("3Y", years_ago(3)),
+            ("ITD", inception),
+        ]
+        .into_iter()
+        .filter(|(_, from)| *from >= inception && *from < end)
+        .collect();
+
+        // Every period is valued at its ends and at the month ends in between.
+        let mut dates: Vec<DateTime<Utc>> = performance::month_ends(inception, end);
+        dates.extend(periods.iter().map(|(_, from)| *from));
+        dates.push(end);
+        dates.sort();
+        dates.dedup();
+
+        let valuations = self.valuations(&stocks, &dates)?;
+
+        let group_of = |stock: &str| stocks.get(stock).map_or("", |s| &s.group[..]);
+        let segments = std::iter::once(Segment::Portfolio)
+            .chain(
+                flows
+                    .iter()
+                    .map(|f| f.account.clone())
+                    .unique()
+                    .sorted()
+                    .map(Segment::Account),
+            )
+            .chain(
+                flows
+                    .iter()
+                    .map(|f| group_of(&f.stock).to_string())
+                    .unique()
+                    .sorted()
+                    .map(Segment::Group),
+            );
+
+        let mut v = Vec::new();
+        for seg in segments {
+            let value = |d: &DateTime<Utc>| -> f64 {
+                valuations[d]
+                    .iter()
+                    .filter(|(account, group, _)| seg.contains(account, group))
+                    .map(|(_, _, amount)| amount)
+                    .sum()
+            };
+            let seg_flows: Vec<(DateTime<Utc>, f64)> = flows
+                .iter()
+                .filter(|f| seg.contains(&f.account, group_of(&f.stock)))
+                .map(|f| (f.date, f.amount))
+                .collect();
+
+            for (period, from) in &periods {
+                let values: Vec<(DateTime<Utc>, f64)> = dates
+                    .iter()
+                    .filter(|d| *d >= from)
+                    .map(|d| (*d, value(d)))
+                    .collect();
+                let in_period: Vec<(DateTime<Utc>, f64)> = seg_flows
+                    .iter()
+                    .filter(|(d, _)| d > from)
+                    .copied()
+                    .collect();
+
+                // From the investor's side: the starting value and contributions are paid in,
+                // withdrawals and the final value are received.
+                let mut cash_flows = vec![(*from, -value(from))];
+                cash_flows.extend(in_period.iter().map(|(d, f)| (*d, -f)));
+                cash_flows.push((end, value(&end)));
+
+                v.push(PerformanceLine {
+                    segment: seg.name(),
+                    period: period.to_string(),
+                    from: *from,
+                    to: end,
+                    twr: performance::twr(&values, &in_period),
+                    irr: performance::xirr(&cash_flows),
+                });
+            }
+        }
+        Ok(v)
+    }
+
+    /// The positions of the portfolio, as of the end of the given date if there is one.
+    // The amount of every position, with its account and group, at each of the dates. The
+    // ledger is walked once in date order and the positions are valued as `port` values them,
+    // at the price and exchange rate of the day.
+    #[allow(clippy::type_complexity)]
+    fn valuations(
+        &self,
+        stocks: &HashMap<String, Stocks>,
+        dates: &[DateTime<Utc>],
+    ) -> Result<HashMap<DateTime<Utc>, Vec<(String, String, f64)>>> {
+        let history = self.load_price_history()?;
+        let ledger = self.ledger()?;
+        let mut trades: Vec<(u64, &Trade)> = ledger.iter_with_lines().collect();
+        trades.sort_by_key(|(_, t)| t.date);
+        let mut trades = trades.into_iter().peekable();
+
+        // Units held by (account, stock), cash included.
+        let mut units: HashMap<(String, String), f64> = HashMap::new();
+        let mut valuations = HashMap::new();
+        for d in dates {
+            while let Some((line, t)) = trades.next_if(|(_, t)| t.date <= *d) {
+                self.check_trade_stocks(line, t, stocks)?;
+                let amt = t.units * t.price.unwrap_or_default() * t.rate();
+                let held = units
+                    .entry((t.account.clone(), t.stock.clone()))
+                    .or_default();
+                // Dividends, buys and sales of a stock move the cash of its account.
+                let cash_change = match t.r#type {
+                    TradeType::Div => amt,
+                    TradeType::Split => {
+                        *held *= t.split;
+                        0.0
+                    }
+                    TradeType::TrIn => {
+                        *held += t.units;
+                        0.0
+                    }
+                    TradeType::TrOut => {
+                        *held -= t.units;
+                        0.0
+                    }
+                    TradeType::Buy => {
+                        *held += t.units;
+                        -amt
+                    }
+                    TradeType::Sell => {
+                        *held -= t.units;
+                        amt
+                    }
+                };
+                let cash = (t.account.clone(), format!("Cash{}", t.account));
+                if !t.stock.contains("Cash") {
+                    *units.entry(cash).or_default() +=
+                        self.cash_amount(t, cash_change, stocks, &history);
+                }
+            }
+
+            let lines = units
+                .iter()
+                .map(|((account, stock), u)| {
+                    let s = &stocks[stock];
+                    let price = if s.asset == "Cash" {
+                        1.0
+                    } else {
+                        s.ticker
+                            .as_ref()
+                            .and_then(|tk| history.price(tk, Some(*d)))
+                            .map_or(0.0, |p| p.price)
+                    };
+                    let rate = history
+                        .rate(&s.tradedcurrency.to_uppercase(), &self.currency, Some(*d))
+                        .map_or(1.0, |r| r.price);
+                    (account.clone(), s.group.clone(), price * u * rate)
+                })
+                .collect();
+            valuations.insert(*d, lines);
+        }
+        Ok(valuations)
+    }
+
+    pub fn port(
+        &self,
+        all: bool,
+        separate_cash: bool,
+        aggregate: bool,
+        as_of: Option<DateTime<Utc>>,
+    ) -> Result<Vec<PortLine>> {
         let stocks = self.load_stocks()?;
+        let history = self.load_price_history()?;
 
         // Why RefCell below?
         // My best guess is that the code needs to borrow mutably different elements
         // inside the hashtable. We humans know it is safe to do so, but the compiler
         // sees it as multiple mutable borrows of elements of the same structure.
-        let mut lines: HashMap<_, _> = stocks
-            .values()
-            .map(|s| (s.name.clone(), RefCell::new(PortLine::from(s))))
-            .collect();
+        // Lines are keyed by (account, stock), so the same stock held in two accounts
+        // gets two separate lines.
+        let mut lines: HashMap<(String, String), RefCell<PortLine>> = HashMap::new();
+
+        let f = |llines: &mut HashMap<(String, String), RefCell<PortLine>>,
+                 line: u64,
+                 t: &Trade|
+         -> Result<()> {
+            self.check_trade_stocks(line, t, &stocks)?;
+            let key = (t.account.to_string(), t.stock.to_string());
+            let cash_key = if !t.stock.contains("Cash") {
+                Some((t.account.to_string(), format!("Cash{}", t.account)))
+            } else {
+                None
+            };
+
+            // A line is created the first time a stock is traded in an account.
+            for k in std::iter::once(&key).chain(cash_key.as_ref()) {
+                llines.entry(k.clone()).or_insert_with(|| {
+                    let mut l = PortLine::from(&stocks[&k.1]);
+                    l.account = k.0.clone();
+                    RefCell::new(l)
+                });
+            }
 
-        let f = |llines: &mut HashMap<String, RefCell<PortLine>>, t: Trade| {
             // The portfolio line for this stock. The 1st borrow.
-            let mut line = llines.get(t.stock).unwrap().borrow_mut();
+            let mut line = llines.get(&key).unwrap().borrow_mut();
 
             // This holds the cash portfolio line for the account the stock is in.
-            let cash = if !t.stock.contains("Cash") {
-                Some(
-                    llines
-                        .get(&format!("Cash{}", t.account)[..])
-                        .unwrap()
-                        .borrow_mut(), // The 2nd borrow.
-                )
-            } else {
-                None
-            };
+            let cash = cash_key.map(|k| llines.get(&k).unwrap().borrow_mut()); // The 2nd borrow.
 
             // [Note] Randomization is useful if you are demoing the application
             // and don't want to show the value of your portfolio.
@@ -447,66 +1741,99 @@ impl Store<'_> {
             }
 
             // Total amount of the trade appropriately translated and optionally randomized.
-            let amt = |t: &Trade| t.units * y * t.price.unwrap_or_default() * t.currency;
+            let amt = |t: &Trade| t.units * y * t.price.unwrap_or_default() * t.rate();
+            // The same amount in the currency of the account's cash.
+            let cash_amt = |t: &Trade| self.cash_amount(t, amt(t), &stocks, &history);
             line.last_trade = t.date;
 
-            // TODO: this is incorrect as it sets the account to the latest account where a trade
-            // for this stock was made. It should create a different line item for the same stock
-            // in different accounts.
-            line.account = t.account.to_string();
-
             match t.r#type {
                 TradeType::Div => {
-                    line.divs_usd += amt(&t);
+                    line.divs += amt(t);
                     if let Some(mut c) = cash {
-                        c.units += amt(&t);
-                        c.divs_usd += amt(&t);
+                        c.units += cash_amt(t);
+                        c.divs += amt(t);
                     }
                 }
-                TradeType::Split => line.units = line.units * t.split,
+                TradeType::Split => line.units *= t.split,
                 TradeType::TrIn => {
                     line.units += t.units;
-                    line.revenue_usd += amt(&t);
+                    line.revenue += amt(t);
                 }
                 TradeType::TrOut => {
                     line.units -= t.units;
-                    line.cost_usd += amt(&t);
+                    line.cost += amt(t);
                 }
                 TradeType::Buy => {
                     line.units += t.units;
-                    line.fees_usd += t.fees.unwrap_or_default() * t.currency;
-                    line.cost_usd += amt(&t);
+                    line.fees += t.fees.unwrap_or_default() * t.rate();
+                    line.cost += amt(t);
 
                     if let Some(mut c) = cash {
-                        c.units -= amt(&t);
-                        c.cost_usd += amt(&t);
-                        c.fees_usd += t.fees.unwrap_or_default();
+                        c.units -= cash_amt(t);
+                        c.cost += amt(t);
+                        c.fees += t.fees.unwrap_or_default();
                     }
                 }
                 TradeType::Sell => {
                     line.units -= t.units;
-                    line.fees_usd += t.fees.unwrap_or_default() * t.currency;
-                    line.revenue_usd += amt(&t);
+                    line.fees += t.fees.unwrap_or_default() * t.rate();
+                    line.revenue += amt(t);
 
                     if let Some(mut c) = cash {
-                        c.units += amt(&t);
-                        c.revenue_usd += amt(&t);
-                        c.fees_usd += t.fees.unwrap_or_default();
+                        c.units += cash_amt(t);
+                        c.revenue += amt(t);
+                        c.fees += t.fees.unwrap_or_default();
                     }
                 }
             }
+            Ok(())
         };
 
-        self.trades_fold(&mut lines, f)?;
+        let ledger = self.ledger()?;
+        for (line, t) in ledger.iter_with_lines() {
+            if as_of.is_none_or(|d| t.date <= d) {
+                f(&mut lines, line, t)?;
+            }
+        }
 
         // At this point lines contains all the positions, including closed ones
         // and cash positions for each account. We can now get their current values
         // using prices, show old prices or currencies and unify the various cash positions
-        let mut ll = lines.into_iter().map(move |(_, v)| v.into_inner());
+        let mut ll: Vec<PortLine> = lines.into_values().map(|v| v.into_inner()).collect();
+
+        // Cost basis of the open lots and gains realized by past sales.
+        let book = self.lots_as_of(as_of)?;
+        let utc_now = as_of.unwrap_or_else(Utc::now);
+        for l in ll.iter_mut() {
+            let open = book.open_lots(&l.account, &l.name);
+            l.basis = open.iter().map(|lot| lot.basis).sum();
+            l.basis_local = open.iter().map(|lot| lot.basis_local()).sum();
+            if open.iter().any(|lot| lot.wash > 0.0) {
+                l.error += "WS";
+            }
+
+            let h = book.holding(&l.account, &l.name, utc_now);
+            l.lt_units = h.lt_units;
+            l.st_units = h.st_units;
+            l.next_lt = h.next_lt;
+        }
+        for s in &book.sales {
+            if let Some(l) = ll
+                .iter_mut()
+                .find(|l| l.account == s.account && l.name == s.stock)
+            {
+                l.realized += s.gain();
+                l.fx_gain += s.fx_gain();
+            }
+        }
+
+        if aggregate {
+            ll = Store::aggregate(ll);
+        }
         let mut v = Vec::new();
 
-        let prices = self.load_prices()?;
-        let utc_now = Utc::now();
+        // Positions are valued with the closest price on or before the date.
+        let price_of = |ticker: &str| history.price(ticker, as_of);
 
         // This contains the total of all cash positions
         let mut total_cash = if separate_cash {
@@ -516,7 +1843,8 @@ impl Store<'_> {
                 account: "Cash".to_string(),
                 ticker: None,
                 name: "_Cash".to_string(),
-                currency: "USD".to_string(),
+                currency: self.currency.clone(),
+                traded_currency: self.currency.clone(),
                 asset: "Cash".to_string(),
                 group: "Cash".to_string(),
                 tags: "Cash".to_string(),
@@ -524,21 +1852,30 @@ impl Store<'_> {
                 units: 0.0,
                 price: 1.0,
                 error: "".to_string(),
-                amount_usd: 0.0,
+                amount: 0.0,
                 amount_perc: 0.0,
-                cost_usd: 0.0,
-                revenue_usd: 0.0,
-                divs_usd: 0.0,
-                fees_usd: 0.0,
+                cost: 0.0,
+                revenue: 0.0,
+                divs: 0.0,
+                fees: 0.0,
+                basis: 0.0,
+                basis_local: 0.0,
+                realized: 0.0,
+                unrealized: 0.0,
                 last_trade: Utc::now(),
                 gain: 0.0,
+                price_gain: 0.0,
+                fx_gain: 0.0,
                 tax_status: "".to_string(),
+                lt_units: 0.0,
+                st_units: 0.0,
+                next_lt: None,
             })
         };
 
-        for mut l in &mut ll {
+        for mut l in ll {
             if let Some(ref pr) = l.ticker {
-                if let Some(p) = prices.get(&pr[..]) {
+                if let Some(p) = price_of(pr) {
                     l.price = p.price;
                     if utc_now - p.date > chrono::Duration::days(5) {
                         l.error += "PO";
@@ -548,42 +1885,43 @@ impl Store<'_> {
             if l.asset == "Cash" {
                 l.price = 1.0;
             }
-            let cur_ticker = format!("{}USD=X", l.currency);
-            let cur_rate = prices.get(&cur_ticker);
-            match cur_rate {
+            let cur_rate = history.rate(&l.traded_currency, &self.currency, as_of);
+            let rate = match cur_rate {
                 Some(r) => {
-                    l.amount_usd = l.price * l.units * r.price;
                     if utc_now - r.date > chrono::Duration::days(5) {
                         l.error += "CO";
                     }
+                    r.price
                 }
                 None => {
-                    l.amount_usd = l.price * l.units;
                     l.error += "CN";
+                    1.0
                 }
-            }
+            };
+            l.amount = l.price * l.units * rate;
 
             if all || Store::is_current_stock(l.units) {
-                if l.asset != "Cash" || (l.asset == "Cash" && separate_cash) {
+                if l.asset != "Cash" || separate_cash {
                     // In the portfolio line, the revenue includes current value.
-                    // This is done so that the gain shown is relative to the price.
-                    l.revenue_usd += l.amount_usd;
-                    //
-                    // Calculates derived values.
+                    l.revenue += l.amount;
+
+                    // The gain is what was realized by sales plus the gain on the open lots.
                     if l.asset != "Cash" {
-                        l.gain = l.revenue_usd - l.cost_usd - l.fees_usd;
-                    };
-                    l.tax_status = if Utc::now() - l.last_trade > chrono::Duration::days(365) {
-                        "LT".to_string()
-                    } else {
-                        "ST".to_string()
+                        l.unrealized = l.amount - l.basis;
+                        l.gain = l.realized + l.unrealized;
+
+                        // The open lots gain on the currency what their local cost is worth now
+                        // more than it cost, the price gain is the rest.
+                        l.fx_gain += l.basis_local * rate - l.basis;
+                        l.price_gain = l.gain - l.fx_gain;
                     };
+                    l.tax_status = Store::tax_status(l.lt_units, l.st_units).to_string();
 
                     v.push(l.clone());
                 } else {
                     if let Some(ref mut c) = total_cash {
                         c.units += l.units;
-                        c.amount_usd += l.units * l.price;
+                        c.amount += l.amount;
                     }
                 }
             }
@@ -594,134 +1932,599 @@ impl Store<'_> {
         }
 
         // [todo] Refactor to unify with self.total.
-        let total = v.iter().fold(0.0, |sum, l| sum + l.amount_usd);
-        v.iter_mut().for_each(|mut l| {
-            l.amount_perc = l.amount_usd / total;
+        let total = v.iter().fold(0.0, |sum, l| sum + l.amount);
+        v.iter_mut().for_each(|l| {
+            l.amount_perc = l.amount / total;
         });
 
         Ok(v)
     }
+    // Merges the lines for the same stock held in different accounts into a single line.
+    fn aggregate(mut lines: Vec<PortLine>) -> Vec<PortLine> {
+        lines.sort_by(|a, b| a.account.cmp(&b.account));
+
+        let mut merged: HashMap<String, PortLine> = HashMap::new();
+        for l in lines {
+            match merged.get_mut(&l.name) {
+                Some(m) => m.merge(&l),
+                None => {
+                    merged.insert(l.name.clone(), l);
+                }
+            }
+        }
+        merged.into_values().collect()
+    }
+
+    // LT if all the open lots are long-term, ST if all are short-term, MX if mixed.
+    fn tax_status(lt_units: f64, st_units: f64) -> &'static str {
+        match (
+            Store::is_current_stock(lt_units),
+            Store::is_current_stock(st_units),
+        ) {
+            (true, true) => "MX",
+            (true, false) => "LT",
+            (false, true) => "ST",
+            (false, false) => "",
+        }
+    }
+
     fn is_current_stock(units: f64) -> bool {
-        units > 0.01 || units < -0.01
+        !(-0.01..=0.01).contains(&units)
     }
 
     fn create_file_if_not_exist(&self, file_name: &str, header: &str) -> crate::errors::Result<()> {
         let full_path = self.home_dir.join(file_name);
         let str_path = full_path.to_string_lossy();
 
-        let mut res = fs::OpenOptions::new()
-            .write(true)
-            .create_new(true)
-            .open(&full_path);
-
-        match &mut res {
-            Ok(file) => {
-                info!("{}: file created", str_path);
-                Ok(writeln!(file, "{}", header)
-                    .chain_err(|| format!("Cannot write to file {}", str_path))?)
-            }
-            Err(e) => {
-                if e.kind() == io::ErrorKind::AlreadyExists {
-                    Ok(warn!("{}: file already exists", str_path))
-                } else {
-                    res.map(|_| ())
-                        .chain_err(|| format!("Error opening {}", str_path))
-                }
-            }
+        if full_path.exists() {
+            warn!("{}: file already exists", str_path);
+            return Ok(());
         }
+        files::write_atomic(&full_path, format!("{}\n", header).as_bytes())?;
+        info!("{}: file created", str_path);
+        Ok(())
+    }
+
+    /// Opens the portfolio to read and change it, while no other lupo uses it.
+    pub fn open(home_dir: &path::Path) -> Result<Store<'_>> {
+        Store::open_locked(home_dir, true)
+    }
+
+    /// Opens the portfolio to read it only, while other lupos may read it too. Changing it
+    /// fails.
+    pub fn open_shared(home_dir: &path::Path) -> Result<Store<'_>> {
+        Store::open_locked(home_dir, false)
     }
 
-    pub fn open(home_dir: &path::Path) -> Result<Store> {
+    fn open_locked(home_dir: &path::Path, exclusive: bool) -> Result<Store<'_>> {
         if home_dir.is_dir() {
-            Ok(Store { home_dir })
+            let lock = files::lock_dir(home_dir, exclusive)?;
+            let config = Store::load_config(home_dir)?;
+            let currency = config
+                .get("currency")
+                .map_or(DEFAULT_CURRENCY, |c| &c[..])
+                .to_uppercase();
+            let kind = match config.get("storage") {
+                Some(s) => s.parse()?,
+                None => storage::StorageKind::default(),
+            };
+            Ok(Store {
+                home_dir,
+                currency,
+                lenient: false,
+                edits: RefCell::new(HashMap::new()),
+                lock,
+                storage: storage::open(kind, home_dir)?,
+                ledger: RefCell::new(None),
+            })
         } else {
             error_chain::bail!("Can't find home directory {}", home_dir.to_string_lossy())
         }
     }
 
-    pub fn new(home_dir: &path::Path, force: bool) -> Result<Store> {
+    pub fn new(home_dir: &path::Path, force: bool) -> Result<Store<'_>> {
         if force && home_dir.is_dir() {
-            fs::remove_dir_all(&home_dir).chain_err(|| "Could not remove portfolio directory")?;
+            // Everything goes but the lock file, which is held while the rest is removed.
+            let _lock = files::lock_dir(home_dir, true)?;
+            let entries =
+                fs::read_dir(home_dir).chain_err(|| "Could not read portfolio directory")?;
+            for entry in entries {
+                let entry = entry.chain_err(|| "Could not read portfolio directory")?;
+                let path = entry.path();
+                if entry.file_name() == files::LOCK_FILE {
+                    continue;
+                }
+                let removed = if path.is_dir() {
+                    fs::remove_dir_all(&path)
+                } else {
+                    fs::remove_file(&path)
+                };
+                removed.chain_err(|| format!("Could not remove {}", path.to_string_lossy()))?;
+            }
         }
         let home_dir_str = home_dir.to_string_lossy();
 
-        let _ = fs::create_dir_all(&home_dir)
+        let _ = fs::create_dir_all(home_dir)
             .chain_err(|| format!("Can't create porfolio directory at {}", home_dir_str));
+        let lock = files::lock_dir(home_dir, true)?;
+
+        let config_header = "Name	Value";
+        let store = Store {
+            home_dir,
+            currency: DEFAULT_CURRENCY.to_string(),
+            lenient: false,
+            edits: RefCell::new(HashMap::new()),
+            lock,
+            storage: Box::new(storage::TsvStorage::new(home_dir)),
+            ledger: RefCell::new(None),
+        };
 
-        let store = Store { home_dir };
-
-        let trade_header = "Account	Date	Type	Stock	Units	Price	Fees	Split	Currency";
-        let stocks_header = "Name	Asset	Group	Tags	Riskyness	Ticker	Tradedcurrency	Currencyunderlying";
+        let trade_header = TRADE_COLUMNS.join("\t");
+        let accounts_header = "Name	Lotmethod	Jurisdiction";
+        let jurisdictions_header = "Name	Longtermdays	Washsaledays";
+        let stocks_header =
+            "Name	Asset	Group	Tags	Riskyness	Ticker	Tradedcurrency	Currencyunderlying	Identical	Provider";
 
         store.create_file_if_not_exist(STOCKS_FILE, stocks_header)?;
-        store.create_file_if_not_exist(TRADES_FILE, trade_header)?;
+        store.create_file_if_not_exist(TRADES_FILE, &trade_header)?;
+        store.create_file_if_not_exist(ACCOUNTS_FILE, accounts_header)?;
+        store.create_file_if_not_exist(JURISDICTIONS_FILE, jurisdictions_header)?;
+        store.create_file_if_not_exist(
+            CONFIG_FILE,
+            &format!("{}\nCurrency	{}", config_header, DEFAULT_CURRENCY),
+        )?;
+
+        // A store that was not forced anew keeps its config.
+        Store::open(home_dir)
+    }
 
-        Ok(store)
+    /// The price provider of the given kind.
+    pub fn price_provider(
+        &self,
+        kind: ProviderKind,
+        quotes_dir: Option<&path::Path>,
+    ) -> Result<Box<dyn PriceProvider>> {
+        Ok(match kind {
+            ProviderKind::Yahoo => Box::new(providers::Yahoo),
+            ProviderKind::File => {
+                let dir = quotes_dir.map_or_else(|| self.home_dir.join(QUOTES_DIR), |d| d.into());
+                Box::new(providers::QuoteFiles::open(&dir)?)
+            }
+        })
     }
 
-    pub async fn update_prices(&self) -> Result<()> {
-        let mut tasks = Vec::new();
+    // The tickers to download with the provider for each of them, stocks use the one in their
+    // 'Provider' column, or `default_provider` if it is empty. Currencies are quoted in the base
+    // currency and always use `default_provider`, the dollar too as trade rates are into it.
+    fn price_tickers(&self, default_provider: ProviderKind) -> Result<Vec<(String, ProviderKind)>> {
         let stocks = self.load_stocks()?;
-        let tickers_port = stocks.values().map(|l| l.ticker.clone());
-
-        let currencies = vec!["EURUSD=X", "GBPUSD=X", "CADUSD=X", "SGDUSD=X", "HKDUSD=X"];
-        let tickers = tickers_port.chain(currencies.iter().map(|t| Some(t.to_string())));
-
-        for ticker in tickers {
-            match ticker {
-                Some(ticker) => {
-                    let task = async move {
-                        let bars = history::retrieve_interval(&ticker[..], Interval::_5d)
-                            .await
-                            .chain_err(|| format!("Error retrieving prices for {}", ticker));
-                        let close = bars.map(|v| v.last().map(|b| (b.datetime(), b.close)));
-                        let c = match close {
-                            Ok(Some(x)) => Ok(x),
-                            Ok(None) => {
-                                Err(Error::from(format!("Empty prices returned for {}", ticker)))
-                            }
-                            Err(e) => Err(e),
-                        };
-                        (ticker, c)
-                    };
+        let tickers_port = stocks.values().filter_map(|l| {
+            l.ticker
+                .clone()
+                .map(|t| (t, l.provider.unwrap_or(default_provider)))
+        });
+
+        // Every currency a stock is traded in or exposed to.
+        let currencies: std::collections::BTreeSet<String> = stocks
+            .values()
+            .flat_map(|s| vec![&s.tradedcurrency, &s.currencyunderlying])
+            .map(|c| c.to_uppercase())
+            .chain(std::iter::once(RATE_CURRENCY.to_string()))
+            .filter(|c| !c.is_empty() && *c != self.currency)
+            .collect();
+        let fx = currencies
+            .iter()
+            .map(|c| (prices::fx_ticker(c, &self.currency), default_provider));
+        Ok(tickers_port.chain(fx).collect())
+    }
+
+    // The two pairs through the pivot currency for each exchange rate that couldn't be
+    // downloaded, so that it can be valued as a cross rate.
+    fn cross_legs(
+        &self,
+        failed: &[String],
+        tried: &HashSet<String>,
+        default_provider: ProviderKind,
+    ) -> Vec<(String, ProviderKind)> {
+        failed
+            .iter()
+            .filter_map(|t| prices::fx_pair(t))
+            .flat_map(|(from, to)| {
+                vec![
+                    (from, prices::FX_PIVOT.to_string()),
+                    (prices::FX_PIVOT.to_string(), to),
+                ]
+            })
+            .filter(|(from, to)| from != to)
+            .map(|(from, to)| prices::fx_ticker(&from, &to))
+            .filter(|t| !tried.contains(t))
+            .unique()
+            .map(|t| (t, default_provider))
+            .collect()
+    }
+
+    fn price_providers(
+        &self,
+        tickers: &[(String, ProviderKind)],
+        quotes_dir: Option<&path::Path>,
+    ) -> Result<HashMap<ProviderKind, Box<dyn PriceProvider>>> {
+        let mut providers = HashMap::new();
+        for (_, kind) in tickers {
+            if !providers.contains_key(kind) {
+                providers.insert(*kind, self.price_provider(*kind, quotes_dir)?);
+            }
+        }
+        Ok(providers)
+    }
+
+    // The base currency is worth one of itself, so that lines in it are valued like the others.
+    fn base_rate(&self, date: DateTime<Utc>) -> PriceLine {
+        PriceLine {
+            date,
+            price: 1.0,
+            ticker: prices::fx_ticker(&self.currency, &self.currency),
+        }
+    }
 
-                    tasks.push(task);
+    /// Updates the latest price of all stocks and currencies.
+    pub async fn update_prices(
+        &self,
+        default_provider: ProviderKind,
+        quotes_dir: Option<&path::Path>,
+    ) -> Result<Vec<PriceUpdate>> {
+        let (lines, updates) = self
+            .fetch_prices(default_provider, quotes_dir, |_| None)
+            .await?;
+        self.append_prices(
+            std::iter::once(self.base_rate(Utc::now()))
+                .chain(lines)
+                .collect(),
+        )?;
+        Ok(updates)
+    }
+
+    /// Downloads the daily prices of every ticker since its first trade, currencies since the
+    /// first trade of the portfolio. Tickers already backfilled resume from their last price.
+    pub async fn backfill_prices(
+        &self,
+        default_provider: ProviderKind,
+        quotes_dir: Option<&path::Path>,
+    ) -> Result<Vec<PriceUpdate>> {
+        let history = if self.storage.exists(PRICES_FILE)? {
+            self.load_price_history()?
+        } else {
+            prices::PriceHistory::default()
+        };
+
+        // The first trade of each ticker and of the whole portfolio.
+        let stocks = self.load_stocks()?;
+        let mut first_trades: HashMap<String, DateTime<Utc>> = HashMap::new();
+        for t in self.ledger()?.iter() {
+            let ticker = stocks.get(&t.stock).and_then(|s| s.ticker.clone());
+            for k in ticker.into_iter().chain(std::iter::once("".to_string())) {
+                let d = first_trades.entry(k).or_insert(t.date);
+                if t.date < *d {
+                    *d = t.date;
                 }
-                None => (),
-            };
+            }
         }
+        let first_trade = match first_trades.get("") {
+            Some(d) => *d,
+            None => return Ok(Vec::new()),
+        };
 
-        let results = futures::future::join_all(tasks).await;
+        let from = |ticker: &str| {
+            let first = first_trades.get(ticker).copied().unwrap_or(first_trade);
 
-        let mut lines = Vec::new();
+            // Prices close to the first trade mean a previous backfill got this ticker.
+            let backfilled = history
+                .at(ticker, first + chrono::Duration::days(5))
+                .is_some();
+            match history.last_date(ticker) {
+                Some(last) if backfilled => Some(last + chrono::Duration::days(1)),
+                _ => Some(first),
+            }
+        };
+        let (lines, updates) = self
+            .fetch_prices(default_provider, quotes_dir, from)
+            .await?;
+        self.append_prices(
+            std::iter::once(self.base_rate(first_trade))
+                .chain(lines)
+                .collect(),
+        )?;
+        Ok(updates)
+    }
 
-        lines.push(PriceLine {
-            date: Utc::now(),
-            price: 1.0,
-            ticker: "USDUSD=X".to_string(),
-        });
+    // The prices of every ticker, the daily ones since the date `from` gives for the ticker or
+    // the latest one without a date, with what each download returned. Exchange rates that fail
+    // are tried again as crosses through the pivot currency.
+    async fn fetch_prices(
+        &self,
+        default_provider: ProviderKind,
+        quotes_dir: Option<&path::Path>,
+        from: impl Fn(&str) -> Option<DateTime<Utc>>,
+    ) -> Result<(Vec<PriceLine>, Vec<PriceUpdate>)> {
+        let mut tickers = self.price_tickers(default_provider)?;
+        let providers = self.price_providers(&tickers, quotes_dir)?;
 
-        for res in results {
-            match res.1 {
-                Ok(bar) => {
-                    let price_line = PriceLine {
-                        ticker: res.0,
-                        date: bar.0,
-                        price: bar.1,
+        let mut lines = Vec::new();
+        let mut updates = Vec::new();
+        let mut tried = HashSet::new();
+        while !tickers.is_empty() {
+            tried.extend(tickers.iter().map(|(t, _)| t.clone()));
+
+            let mut tasks = Vec::new();
+            for (ticker, kind) in tickers {
+                let provider = &providers[&kind];
+                let from = from(&ticker);
+                let task = async move {
+                    let c = match from {
+                        Some(from) => provider.history(&ticker, from).await,
+                        None => provider.quote(&ticker).await.map(|bar| vec![bar]),
                     };
-                    println!("{}", price_line);
-                    lines.push(price_line);
+                    (ticker, from, c)
+                };
+                tasks.push(task);
+            }
+
+            let results = futures::future::join_all(tasks).await;
+
+            let mut failed = Vec::new();
+            for (ticker, from, res) in results {
+                let prices = res.map(|bars| {
+                    bars.into_iter()
+                        .map(|(date, price)| PriceLine {
+                            ticker: ticker.clone(),
+                            date,
+                            price,
+                        })
+                        .collect::<Vec<_>>()
+                });
+                match &prices {
+                    Ok(p) => lines.extend(p.iter().cloned()),
+                    Err(_) => failed.push(ticker.clone()),
+                }
+                updates.push(PriceUpdate {
+                    ticker,
+                    from,
+                    prices,
+                });
+            }
+            tickers = self.cross_legs(&failed, &tried, default_provider);
+        }
+        Ok((lines, updates))
+    }
+
+    /// Edits the trades file, see `edit_file`.
+    pub fn edit_trades(
+        &self,
+        on_errors: impl FnMut(&[String]) -> Result<EditChoice>,
+    ) -> Result<bool> {
+        self.edit_file(TRADES_FILE, on_errors)
+    }
+    /// Edits the stocks file, see `edit_file`.
+    pub fn edit_stocks(
+        &self,
+        on_errors: impl FnMut(&[String]) -> Result<EditChoice>,
+    ) -> Result<bool> {
+        self.edit_file(STOCKS_FILE, on_errors)
+    }
+
+    /// Opens a copy of the file in the default editor and checks it when the editor exits.
+    /// If there are errors, `on_errors` chooses to edit the copy again, discard it or save it
+    /// anyway. The file is replaced only when the copy is saved, returning true.
+    pub fn edit_file(
+        &self,
+        file_name: &str,
+        mut on_errors: impl FnMut(&[String]) -> Result<EditChoice>,
+    ) -> Result<bool> {
+        self.check_writable()?;
+        let copy = files::edit_path(&self.home_dir.join(file_name));
+        let before = self
+            .storage
+            .export(file_name)?
+            .ok_or_else(|| format!("Can't find {}", file_name))?;
+        fs::write(&copy, &before).chain_err(|| format!("Can't copy {}", file_name))?;
+
+        let saved = self.edit_copy(file_name, &copy, &before, &mut on_errors);
+        // The copy is removed whether the edit was saved, discarded or failed.
+        let removed =
+            fs::remove_file(&copy).chain_err(|| format!("Can't remove the copy of {}", file_name));
+        let saved = saved?;
+        removed?;
+        Ok(saved)
+    }
+
+    // Edits the copy until it has no errors or the user chooses what to do with them, then
+    // replaces the file with it unless the changes are discarded.
+    fn edit_copy(
+        &self,
+        file_name: &str,
+        copy: &path::Path,
+        before: &[u8],
+        on_errors: &mut impl FnMut(&[String]) -> Result<EditChoice>,
+    ) -> Result<bool> {
+        let choice = loop {
+            edit::edit_file(copy).chain_err(|| "Can't open default editor")?;
+            let errors = self.check_edit(file_name, copy);
+            if errors.is_empty() {
+                break EditChoice::Save;
+            }
+            match on_errors(&errors)? {
+                EditChoice::Edit => continue,
+                choice => break choice,
+            }
+        };
+
+        self.invalidate_ledger();
+        if choice == EditChoice::Discard {
+            return Ok(false);
+        }
+        let after = fs::read(copy).chain_err(|| format!("Can't read the copy of {}", file_name))?;
+        journal::record(self.home_dir, file_name, "edit", before, &after)?;
+        self.storage.import(file_name, &after)?;
+        Ok(true)
+    }
+
+    // Changes are only made to a portfolio opened to change it.
+    fn check_writable(&self) -> Result<()> {
+        if !self.lock.is_exclusive() {
+            error_chain::bail!(
+                "The portfolio in {} is open to read only",
+                self.home_dir.to_string_lossy()
+            );
+        }
+        Ok(())
+    }
+
+    // Replaces a file with new contents, recording the change in the journal.
+    fn replace_file(&self, file_name: &str, contents: &[u8], command: &str) -> Result<()> {
+        self.check_writable()?;
+        let before = self.storage.export(file_name)?.unwrap_or_default();
+        journal::record(self.home_dir, file_name, command, &before, contents)?;
+        self.storage.import(file_name, contents)?;
+        self.invalidate_ledger();
+        Ok(())
+    }
+
+    /// The changes made to the trades and stocks files, oldest first.
+    pub fn history(&self) -> Result<Vec<journal::Change>> {
+        journal::changes(self.home_dir)
+    }
+
+    /// The lines a change removed and added.
+    pub fn change_diff(&self, id: u64) -> Result<String> {
+        journal::diff(self.home_dir, id)
+    }
+
+    /// Reverts the last `n` changes not undone yet, returning them. Undoing is recorded as a
+    /// change too, but it is not undone in turn: undoing again goes further back.
+    /// A file changed since its last recorded change is only reverted with `force`.
+    pub fn undo(&self, n: usize, force: bool) -> Result<Vec<journal::Change>> {
+        let changes = self.history()?;
+        let undone_before: HashSet<u64> = changes.iter().flat_map(journal::undone_ids).collect();
+        let undoable: Vec<&journal::Change> = changes
+            .iter()
+            .filter(|c| journal::undone_ids(c).is_empty() && !undone_before.contains(&c.id))
+            .collect();
+        if n > undoable.len() {
+            error_chain::bail!("There are only {} changes to undo", undoable.len());
+        }
+        let undone: Vec<journal::Change> = undoable[undoable.len() - n..]
+            .iter()
+            .map(|c| (*c).clone())
+            .collect();
+        let command = format!("undo {}", undone.iter().map(|c| c.id).join(","));
+
+        // The files must be as the journal last left them, storages may quote them differently.
+        if !force {
+            for file in undone.iter().map(|c| &c.file).unique() {
+                let last = changes.iter().rev().find(|c| c.file == *file).unwrap();
+                let current = self.storage.export(file)?.unwrap_or_default();
+                let latest = journal::latest(self.home_dir, file)?.unwrap_or_default();
+                if !tsv::same_rows(&current, &latest) {
+                    error_chain::bail!(
+                        "{} has changed since change {}, use --force to undo anyway",
+                        file,
+                        last.id
+                    );
                 }
-                Err(e) => println!("{}", e),
             }
         }
 
-        self.write_prices(lines)
+        // Each file goes back to how it was before the oldest change undone.
+        for (i, c) in undone.iter().enumerate() {
+            if undone[..i].iter().all(|o| o.file != c.file) {
+                let before = journal::before(self.home_dir, c)?;
+                self.replace_file(&c.file, &before, &command)?;
+            }
+        }
+        Ok(undone)
+    }
+
+    // The errors `check` finds with the copy in place of the file.
+    fn check_edit(&self, file_name: &str, copy: &path::Path) -> Vec<String> {
+        self.edits
+            .borrow_mut()
+            .insert(file_name.to_string(), copy.to_path_buf());
+        self.invalidate_ledger();
+
+        let result = self
+            .check_errors()
+            .and_then(|errors| self.check().map(|_| errors));
+        let errors = match result {
+            Ok(errors) => errors.iter().map(|e| e.to_string()).collect(),
+            Err(e) => vec![e.to_string()],
+        };
+
+        self.edits.borrow_mut().remove(file_name);
+        self.invalidate_ledger();
+        errors
     }
-    pub fn edit_trades(&self) -> Result<()> {
-        edit::edit_file(self.home_dir.join(TRADES_FILE)).chain_err(|| "Can't open default editor")
+
+    // The rows of a table, from the copy of it being edited if there is one.
+    fn read_table(&self, table: &str) -> Result<Option<tsv::Table>> {
+        match self.edits.borrow().get(table) {
+            Some(copy) => {
+                let mut rdr = tsv::reader()
+                    .from_path(copy)
+                    .chain_err(|| format!("Cannot open the copy of {}", table))?;
+                tsv::read_table(&mut rdr, table).map(Some)
+            }
+            None => self.storage.read(table),
+        }
     }
-    pub fn edit_stocks(&self) -> Result<()> {
-        edit::edit_file(self.home_dir.join(STOCKS_FILE)).chain_err(|| "Can't open default editor")
+
+    // How rows of a table are located in errors.
+    fn location(&self, table: &str) -> String {
+        if self.edits.borrow().contains_key(table) {
+            table.to_string()
+        } else {
+            self.storage.location(table)
+        }
+    }
+
+    /// Copies the tables to the storage of the kind, which the portfolio uses from then on.
+    /// The tables are left in the storage they are copied from.
+    pub fn migrate(&self, to: storage::StorageKind) -> Result<usize> {
+        self.check_writable()?;
+        let target = storage::open(to, self.home_dir)?;
+        let tables = [
+            STOCKS_FILE,
+            TRADES_FILE,
+            ACCOUNTS_FILE,
+            JURISDICTIONS_FILE,
+            PRICES_FILE,
+        ];
+        let mut copied = 0;
+        for table in tables.iter() {
+            if let Some(text) = self.storage.export(table)? {
+                target.import(table, &text)?;
+                copied += 1;
+            }
+        }
+
+        // The config file says which storage to open.
+        let path = self.home_dir.join(CONFIG_FILE);
+        let mut config = if path.exists() {
+            let mut rdr = tsv::reader()
+                .from_path(&path)
+                .chain_err(|| "Cannot open config file")?;
+            tsv::read_table(&mut rdr, CONFIG_FILE)?
+        } else {
+            tsv::Table {
+                headers: csv::StringRecord::from(vec!["Name", "Value"]),
+                rows: Vec::new(),
+            }
+        };
+        config
+            .rows
+            .retain(|(_, r)| !r.get(0).is_some_and(|n| n.eq_ignore_ascii_case("storage")));
+        let setting = csv::StringRecord::from(vec!["Storage".to_string(), to.to_string()]);
+        config.rows.push((0, setting));
+        files::write_atomic(&path, &tsv::render(&config)?)?;
+        self.invalidate_ledger();
+        Ok(copied)
     }
 }
@@ -0,0 +1,515 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, Duration, Utc};
+use serde::Deserialize;
+
+use crate::errors::*;
+use crate::{Account, Jurisdiction, Stocks, Trade, TradeType};
+
+/// How a `Sell`/`TrOut` picks the open lots it closes.
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize)]
+pub enum LotMethod {
+    /// First in, first out
+    #[default]
+    Fifo,
+    /// Last in, first out
+    Lifo,
+    /// Average cost: every open lot is consumed in proportion to its units
+    Average,
+    /// The lot acquired on the date in the trade's `Lot` column, then FIFO for the rest
+    Specific,
+}
+
+/// An open position in a stock, acquired on a given date.
+#[derive(Debug, Clone)]
+pub struct Lot {
+    pub account: String,
+    pub stock: String,
+    pub acquired: DateTime<Utc>,
+    pub units: f64,
+    /// Cost of the lot including fees and losses disallowed by wash sales.
+    pub basis: f64,
+    /// Loss disallowed by a wash sale and added to the basis of this lot.
+    pub wash: f64,
+    /// Rate of the traded currency into the base currency when the lot was acquired.
+    pub fx_rate: f64,
+    /// Units already used as replacement for a wash sale.
+    replaced_units: f64,
+}
+
+/// The part of a lot closed by a sale.
+#[derive(Debug, Clone)]
+pub struct LotMatch {
+    pub acquired: DateTime<Utc>,
+    pub units: f64,
+    pub basis: f64,
+    pub fx_rate: f64,
+}
+
+/// Units of a position held long-term and short-term.
+#[derive(Debug, Clone, Default)]
+pub struct Holding {
+    pub lt_units: f64,
+    pub st_units: f64,
+    /// When the oldest short-term lot becomes long-term.
+    pub next_lt: Option<DateTime<Utc>>,
+}
+
+/// A `Sell`, or a `TrOut` no account received, with the lots it closed.
+#[derive(Debug, Clone)]
+pub struct Sale {
+    pub account: String,
+    pub stock: String,
+    pub date: DateTime<Utc>,
+    pub units: f64,
+    pub proceeds: f64,
+    pub fees: f64,
+    /// Loss not deductible because replacement shares were bought within the wash sale window.
+    pub wash: f64,
+    /// Rate of the traded currency into the base currency on the day of the sale.
+    pub fx_rate: f64,
+    pub lots: Vec<LotMatch>,
+}
+
+// A loss waiting for replacement shares bought after the sale.
+#[derive(Debug)]
+struct PendingLoss {
+    sale: usize,
+    identity: String,
+    date: DateTime<Utc>,
+    window: Duration,
+    units: f64,
+    loss_per_unit: f64,
+}
+
+// The part of a gain due to the change of the exchange rate between acquisition and `rate`.
+fn fx_gain(basis: f64, acquired_rate: f64, rate: f64) -> f64 {
+    if acquired_rate.abs() < EPSILON {
+        0.0
+    } else {
+        basis * (rate / acquired_rate - 1.0)
+    }
+}
+
+impl Lot {
+    pub fn price(&self) -> f64 {
+        self.basis / self.units
+    }
+    /// Cost of the lot in the traded currency.
+    pub fn basis_local(&self) -> f64 {
+        if self.fx_rate.abs() < EPSILON {
+            0.0
+        } else {
+            self.basis / self.fx_rate
+        }
+    }
+}
+
+impl Sale {
+    pub fn basis(&self) -> f64 {
+        self.lots.iter().map(|m| m.basis).sum()
+    }
+    /// Realized gain, net of the losses disallowed by wash sales.
+    pub fn gain(&self) -> f64 {
+        self.proceeds - self.fees - self.basis() + self.wash
+    }
+    /// The part of the gain due to the exchange rate, the rest comes from the price.
+    pub fn fx_gain(&self) -> f64 {
+        self.lots
+            .iter()
+            .map(|m| fx_gain(m.basis, m.fx_rate, self.fx_rate))
+            .sum()
+    }
+}
+
+/// Open lots and realized sales, built by applying trades in chronological order.
+#[derive(Debug, Default)]
+pub struct LotBook {
+    methods: HashMap<String, LotMethod>,
+    long_term_days: HashMap<String, i64>,
+    wash_sale_days: HashMap<String, i64>,
+    identities: HashMap<String, String>,
+    pending: Vec<PendingLoss>,
+    // Lots moved out of an account by a `TrOut`, until a `TrIn` moves them in.
+    transfers: Vec<Transfer>,
+    pub open: HashMap<(String, String), Vec<Lot>>,
+    pub sales: Vec<Sale>,
+}
+
+// Lots on their way between accounts, with the sale they are if no account receives them.
+#[derive(Debug)]
+struct Transfer {
+    sale: Sale,
+    lots: Vec<Lot>,
+}
+
+// Lots smaller than this are considered closed.
+const EPSILON: f64 = 1e-9;
+
+/// Holding period for accounts without a known jurisdiction (US rules).
+pub const DEFAULT_LONG_TERM_DAYS: i64 = 365;
+
+/// Days before and after a loss in which buying replacement shares makes it a wash sale (US rules).
+pub const DEFAULT_WASH_SALE_DAYS: i64 = 30;
+
+impl LotBook {
+    pub fn new(
+        accounts: &HashMap<String, Account>,
+        jurisdictions: &HashMap<String, Jurisdiction>,
+        stocks: &HashMap<String, Stocks>,
+    ) -> LotBook {
+        LotBook {
+            methods: accounts
+                .values()
+                .map(|a| (a.name.clone(), a.lotmethod))
+                .collect(),
+            long_term_days: accounts
+                .values()
+                .filter_map(|a| {
+                    let j = jurisdictions.get(a.jurisdiction.as_ref()?)?;
+                    Some((a.name.clone(), j.longtermdays))
+                })
+                .collect(),
+            wash_sale_days: accounts
+                .values()
+                .filter_map(|a| {
+                    let j = jurisdictions.get(a.jurisdiction.as_ref()?)?;
+                    Some((a.name.clone(), j.washsaledays?))
+                })
+                .collect(),
+            // Stocks tagged with the same 'Identical' value are substantially identical.
+            identities: stocks
+                .values()
+                .map(|s| {
+                    let id = s.identical.clone().unwrap_or_else(|| s.name.clone());
+                    (s.name.clone(), id)
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    /// Days a lot in the account must be held to become long-term.
+    pub fn long_term_days(&self, account: &str) -> i64 {
+        self.long_term_days
+            .get(account)
+            .copied()
+            .unwrap_or(DEFAULT_LONG_TERM_DAYS)
+    }
+
+    pub fn wash_sale_days(&self, account: &str) -> i64 {
+        self.wash_sale_days
+            .get(account)
+            .copied()
+            .unwrap_or(DEFAULT_WASH_SALE_DAYS)
+    }
+
+    fn identity<'a>(&'a self, stock: &'a str) -> &'a str {
+        self.identities.get(stock).map_or(stock, |s| &s[..])
+    }
+
+    /// Splits the open lots of a position in long-term and short-term units as of `now`.
+    pub fn holding(&self, account: &str, stock: &str, now: DateTime<Utc>) -> Holding {
+        let period = Duration::days(self.long_term_days(account));
+        let mut h = Holding::default();
+
+        for l in self.open_lots(account, stock) {
+            let lt_date = l.acquired + period;
+            if now > lt_date {
+                h.lt_units += l.units;
+            } else {
+                h.st_units += l.units;
+                if h.next_lt.is_none_or(|d| lt_date < d) {
+                    h.next_lt = Some(lt_date);
+                }
+            }
+        }
+        h
+    }
+
+    pub fn open_lots(&self, account: &str, stock: &str) -> &[Lot] {
+        self.open
+            .get(&(account.to_string(), stock.to_string()))
+            .map_or(&[], |v| &v[..])
+    }
+
+    /// Applies the trade to the lots of its position. Selling more units than are held, or a
+    /// specific lot that isn't open, is an error and leaves the lots as they were.
+    pub fn apply(&mut self, t: &Trade) -> Result<()> {
+        self.dispose_transfers(Some(t.date));
+
+        // Cash lines are not taxable lots.
+        if t.stock.contains("Cash") {
+            return Ok(());
+        }
+
+        let amt = t.units * t.price.unwrap_or_default() * t.rate();
+        let fees = t.fees.unwrap_or_default() * t.rate();
+        let key = (t.account.to_string(), t.stock.to_string());
+
+        match t.r#type {
+            // Lots transferred out of another account the same day keep their basis and
+            // acquisition date, other units are acquired at the trade price.
+            TradeType::TrIn => {
+                let matching = self.transfers.iter().position(|m| {
+                    m.sale.stock == t.stock
+                        && m.sale.date == t.date
+                        && (m.sale.units - t.units).abs() < EPSILON
+                });
+                let lots = match matching {
+                    Some(i) => {
+                        let mut lots = self.transfers.remove(i).lots;
+                        for lot in lots.iter_mut() {
+                            lot.account = t.account.to_string();
+                            lot.basis += fees * lot.units / t.units;
+                        }
+                        lots
+                    }
+                    None => {
+                        let mut lot = Lot {
+                            account: t.account.to_string(),
+                            stock: t.stock.to_string(),
+                            acquired: t.date,
+                            units: t.units,
+                            basis: amt + fees,
+                            wash: 0.0,
+                            fx_rate: t.rate(),
+                            replaced_units: 0.0,
+                        };
+                        self.replace_pending_losses(&mut lot);
+                        vec![lot]
+                    }
+                };
+                let open = self.open.entry(key).or_default();
+                open.extend(lots);
+                open.sort_by_key(|l| l.acquired);
+            }
+            TradeType::Buy => {
+                let mut lot = Lot {
+                    account: t.account.to_string(),
+                    stock: t.stock.to_string(),
+                    acquired: t.date,
+                    units: t.units,
+                    basis: amt + fees,
+                    wash: 0.0,
+                    fx_rate: t.rate(),
+                    replaced_units: 0.0,
+                };
+                self.replace_pending_losses(&mut lot);
+                self.open.entry(key).or_default().push(lot);
+            }
+            TradeType::Sell | TradeType::TrOut => {
+                let method = self.methods.get(&t.account).copied().unwrap_or_default();
+                let lots = self.open.entry(key).or_default();
+                let held: f64 = lots.iter().map(|l| l.units).sum();
+                if t.units - held > EPSILON {
+                    error_chain::bail!(
+                        "{} {}: sold {} units but only {} were held",
+                        t.account,
+                        t.stock,
+                        t.units,
+                        held
+                    );
+                }
+                if let (LotMethod::Specific, Some(spec)) = (method, t.lot.as_deref()) {
+                    if !lots
+                        .iter()
+                        .any(|l| l.acquired.format("%Y/%m/%d").to_string() == spec)
+                    {
+                        error_chain::bail!(
+                            "{} {}: no open lot acquired on {}",
+                            t.account,
+                            t.stock,
+                            spec
+                        );
+                    }
+                }
+                let taken = LotBook::consume(lots, t.units, method, t.lot.as_deref());
+                let matched = taken
+                    .iter()
+                    .map(|l| LotMatch {
+                        acquired: l.acquired,
+                        units: l.units,
+                        basis: l.basis,
+                        fx_rate: l.fx_rate,
+                    })
+                    .collect();
+                let mut sale = Sale {
+                    account: t.account.to_string(),
+                    stock: t.stock.to_string(),
+                    date: t.date,
+                    units: t.units,
+                    proceeds: amt,
+                    fees,
+                    wash: 0.0,
+                    fx_rate: t.rate(),
+                    lots: matched,
+                };
+                // A transfer is not a sale, the lots move to the account receiving them.
+                if t.r#type == TradeType::TrOut {
+                    self.transfers.push(Transfer { sale, lots: taken });
+                    return Ok(());
+                }
+                self.wash_loss(&mut sale);
+                self.sales.push(sale);
+            }
+            // The basis of a lot doesn't change with a split, its price per unit does.
+            TradeType::Split => {
+                if let Some(lots) = self.open.get_mut(&key) {
+                    lots.iter_mut().for_each(|l| {
+                        l.units *= t.split;
+                        l.replaced_units *= t.split;
+                    });
+                }
+            }
+            TradeType::Div => (),
+        }
+        Ok(())
+    }
+
+    /// Turns the transfers out made before `date`, all of them without one, that no `TrIn` of
+    /// the same stock and units received on their day into sales: the lots left the portfolio.
+    pub fn dispose_transfers(&mut self, date: Option<DateTime<Utc>>) {
+        let (disposed, pending) = std::mem::take(&mut self.transfers)
+            .into_iter()
+            .partition(|m| date.is_none_or(|d| m.sale.date < d));
+        self.transfers = pending;
+        for Transfer { mut sale, .. } in disposed {
+            self.wash_loss(&mut sale);
+            self.sales.push(sale);
+        }
+    }
+
+    // A loss is disallowed for the units of substantially identical stock bought within the
+    // window before the sale. The rest waits for purchases in the window after it.
+    fn wash_loss(&mut self, sale: &mut Sale) {
+        let loss = -(sale.proceeds - sale.fees - sale.basis());
+        if loss <= EPSILON || sale.units <= EPSILON {
+            return;
+        }
+        let window = Duration::days(self.wash_sale_days(&sale.account));
+        if window <= Duration::zero() {
+            return;
+        }
+        let loss_per_unit = loss / sale.units;
+        let identity = self.identity(&sale.stock).to_string();
+        let mut units = sale.units;
+
+        // Replacement lots absorb the loss oldest first, whatever position they are in.
+        let mut replacements: Vec<(DateTime<Utc>, (String, String), usize)> = self
+            .open
+            .iter()
+            .filter(|((_, stock), _)| self.identity(stock) == identity)
+            .flat_map(|(key, lots)| {
+                lots.iter()
+                    .enumerate()
+                    .filter(|(_, l)| l.acquired >= sale.date - window && l.acquired <= sale.date)
+                    .map(move |(i, l)| (l.acquired, key.clone(), i))
+            })
+            .collect();
+        replacements.sort();
+
+        for (_, key, i) in replacements {
+            if units <= EPSILON {
+                break;
+            }
+            let l = &mut self.open.get_mut(&key).unwrap()[i];
+            let w = units.min(l.units - l.replaced_units);
+            if w > EPSILON {
+                l.replaced_units += w;
+                l.basis += w * loss_per_unit;
+                l.wash += w * loss_per_unit;
+                sale.wash += w * loss_per_unit;
+                units -= w;
+            }
+        }
+
+        if units > EPSILON {
+            self.pending.push(PendingLoss {
+                sale: self.sales.len(),
+                identity,
+                date: sale.date,
+                window,
+                units,
+                loss_per_unit,
+            });
+        }
+    }
+
+    fn replace_pending_losses(&mut self, lot: &mut Lot) {
+        let identity = self.identity(&lot.stock).to_string();
+        self.pending.retain(|p| lot.acquired - p.date <= p.window);
+
+        for p in self.pending.iter_mut().filter(|p| p.identity == identity) {
+            let w = p.units.min(lot.units - lot.replaced_units);
+            if w > EPSILON {
+                lot.replaced_units += w;
+                lot.basis += w * p.loss_per_unit;
+                lot.wash += w * p.loss_per_unit;
+                self.sales[p.sale].wash += w * p.loss_per_unit;
+                p.units -= w;
+            }
+        }
+        self.pending.retain(|p| p.units > EPSILON);
+    }
+
+    // Takes `units` out of the lot, with their share of its basis.
+    fn split_lot(l: &mut Lot, units: f64) -> Lot {
+        let frac = units / l.units;
+        let part = Lot {
+            units,
+            basis: l.basis * frac,
+            wash: l.wash * frac,
+            replaced_units: l.replaced_units * frac,
+            ..l.clone()
+        };
+        l.units -= part.units;
+        l.basis -= part.basis;
+        l.wash -= part.wash;
+        l.replaced_units -= part.replaced_units;
+        part
+    }
+
+    // The parts of the open lots taken by a sale or transfer of `units`.
+    fn consume(
+        lots: &mut Vec<Lot>,
+        units: f64,
+        method: LotMethod,
+        specific: Option<&str>,
+    ) -> Vec<Lot> {
+        let mut taken = Vec::new();
+
+        if method == LotMethod::Average {
+            let held: f64 = lots.iter().map(|l| l.units).sum();
+            if held > EPSILON {
+                let frac = (units / held).min(1.0);
+                for l in lots.iter_mut() {
+                    let u = l.units * frac;
+                    taken.push(LotBook::split_lot(l, u));
+                }
+            }
+        } else {
+            // The order in which lots are consumed.
+            let mut order: Vec<usize> = (0..lots.len()).collect();
+            if method == LotMethod::Lifo {
+                order.reverse();
+            }
+            if let (LotMethod::Specific, Some(spec)) = (method, specific) {
+                order.sort_by_key(|&i| lots[i].acquired.format("%Y/%m/%d").to_string() != spec);
+            }
+
+            let mut left = units;
+            for i in order {
+                if left <= EPSILON {
+                    break;
+                }
+                let u = left.min(lots[i].units);
+                taken.push(LotBook::split_lot(&mut lots[i], u));
+                left -= u;
+            }
+        }
+
+        lots.retain(|l| l.units > EPSILON);
+        taken
+    }
+}
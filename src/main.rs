@@ -5,6 +5,9 @@ use lupo::*;
 
 use itertools::Itertools;
 use lupo::args::*;
+use lupo::output::{Format, Printer};
+
+mod interactive;
 
 // Rust doesn't trap a unix signal appropriately occasionally: https://github.com/rust-lang/rust/issues/46016
 fn reset_signal_pipe_handler() -> Result<()> {
@@ -20,6 +23,36 @@ fn reset_signal_pipe_handler() -> Result<()> {
 
     Ok(())
 }
+// Opens the store, in the currency given on the command line if there is one. Commands that
+// only read it share it with other lupos, those that `write` hold it alone.
+fn open<'a>(
+    home_dir: &'a std::path::Path,
+    currency: &Option<String>,
+    lenient: bool,
+    write: bool,
+) -> Result<Store<'a>> {
+    let mut store = if write {
+        Store::open(home_dir)?
+    } else {
+        Store::open_shared(home_dir)?
+    };
+    store.lenient = lenient;
+    if let Some(c) = currency {
+        store.currency = c.to_uppercase();
+    }
+    Ok(store)
+}
+
+fn print_all<T: serde::Serialize + std::fmt::Display>(
+    format: Format,
+    header: &str,
+    records: &[T],
+) -> Result<()> {
+    let mut printer = Printer::new(format, header);
+    records.iter().for_each(|r| printer.print(r));
+    printer.finish().map(|_| ())
+}
+
 #[tokio::main]
 async fn main() {
     reset_signal_pipe_handler().unwrap();
@@ -55,6 +88,9 @@ async fn run() -> Result<()> {
         .unwrap();
 
     let home_dir = &opts.directory.unwrap();
+    let currency = opts.currency;
+    let format = opts.format;
+    let lenient = opts.lenient;
 
     match opts.subcmd {
         SubCommand::Init { force } => {
@@ -63,61 +99,150 @@ async fn run() -> Result<()> {
             Ok(())
         }
         SubCommand::Check {} => {
-            let store = Store::open(home_dir)?;
+            let store = open(home_dir, &currency, lenient, false)?;
+            let errors = store.check_errors()?;
+            if !errors.is_empty() {
+                print_all(format, "", &errors)?;
+                error_chain::bail!("{} errors found in the portfolio files", errors.len());
+            }
             let (ct, cs) = store.check()?;
-            println!("{} trades processed correctly.", ct);
-            println!("{} stocks processed correctly.", cs);
-            Ok(())
+            let counts = [
+                CheckLine {
+                    file: "trades".to_string(),
+                    rows: ct,
+                },
+                CheckLine {
+                    file: "stocks".to_string(),
+                    rows: cs,
+                },
+            ];
+            print_all(format, "", &counts)?;
+
+            let washes: Vec<WashSaleLine> = store.wash_sales()?.iter().map(From::from).collect();
+            if washes.is_empty() {
+                return Ok(());
+            }
+            print_all(format, "", &washes)
         }
         SubCommand::Trades {
             name_substring,
             edit,
         } => {
-            let store = Store::open(home_dir)?;
+            let store = open(home_dir, &currency, lenient, edit)?;
 
             if edit {
-                store.edit_trades()
+                store.edit_trades(interactive::edit_choice).map(|_| ())
             } else {
-                println!(
+                let header = format!(
                     fmt_trade!(),
                     "ACCOUNT", "DATE", "TYPE", "UNITS", "NAME", "PRICE", "FEES"
                 );
-                store.trades(name_substring)?;
-                Ok(())
+                print_all(format, &header, &store.trades(name_substring)?)
             }
         }
+        SubCommand::Add {
+            interactive,
+            trade_type,
+            account,
+            stock,
+            units,
+            price,
+            fees,
+            split,
+            date,
+            rate,
+            lot,
+        } => {
+            let store = open(home_dir, &currency, lenient, true)?;
+            let draft = TradeDraft {
+                r#type: trade_type,
+                account,
+                stock,
+                units,
+                price,
+                fees,
+                split,
+                date,
+                rate,
+                lot,
+            };
+            let trade = if interactive {
+                match interactive::prompt_trade(&store, draft)? {
+                    Some(t) => t,
+                    None => return Ok(()),
+                }
+            } else {
+                draft.into_trade()?
+            };
+            let trade = store.add_trade(trade)?;
+            let header = format!(
+                fmt_trade!(),
+                "ACCOUNT", "DATE", "TYPE", "UNITS", "NAME", "PRICE", "FEES"
+            );
+            print_all(format, &header, &[trade])
+        }
+        SubCommand::History { diff } => {
+            if diff && format != Format::Table {
+                error_chain::bail!("--diff can only be shown in the table format");
+            }
+            let store = open(home_dir, &currency, lenient, false)?;
+            let changes = store.history()?;
+            let header = format!(
+                fmt_change!(),
+                "ID", "DATE", "FILE", "COMMAND", "ADDED", "REMOVED"
+            );
+            if !diff {
+                return print_all(format, &header, &changes);
+            }
+            println!("{}", header);
+            for c in changes {
+                println!("{}", c);
+                print!("{}", store.change_diff(c.id)?);
+            }
+            Ok(())
+        }
+        SubCommand::Undo { n, force } => {
+            let store = open(home_dir, &currency, lenient, true)?;
+            let undone = store.undo(n, force)?;
+            let header = format!(
+                fmt_change!(),
+                "ID", "DATE", "FILE", "COMMAND", "ADDED", "REMOVED"
+            );
+            print_all(format, &header, &undone)
+        }
+        SubCommand::Migrate { to } => {
+            let store = open(home_dir, &currency, lenient, true)?;
+            let n = store.migrate(to)?;
+            println!("{} tables copied, the portfolio is now kept in {}.", n, to);
+            Ok(())
+        }
         SubCommand::Stocks {
             name_substring,
             edit,
         } => {
-            let store = Store::open(home_dir)?;
+            let store = open(home_dir, &currency, lenient, edit)?;
             if edit {
-                store.edit_stocks()
+                store.edit_stocks(interactive::edit_choice).map(|_| ())
             } else {
-                store.stocks(name_substring)?;
-                Ok(())
+                print_all(format, "", &store.stocks(name_substring)?)
             }
         }
         SubCommand::Port {
             all,
             separate_cash,
+            aggregate,
+            as_of,
             sort_by,
         } => {
-            let store = Store::open(home_dir)?;
-            let mut v = store.port(all, separate_cash)?;
+            let store = open(home_dir, &currency, lenient, false)?;
+            let mut v = store.port(all, separate_cash, aggregate, as_of)?;
 
             if let Some(sort_by_field) = sort_by {
                 match sort_by_field {
                     SortField::Account => v.sort_by(|a, b| a.account.cmp(&b.account)),
-                    SortField::Amount => {
-                        v.sort_by(|a, b| b.amount_usd.partial_cmp(&a.amount_usd).unwrap())
-                    },
-                    SortField::Pr => {
-                        v.sort_by(|a, b| a.price.partial_cmp(&b.price).unwrap())
-                    },
-                    SortField::Units => {
-                        v.sort_by(|a, b| a.units.partial_cmp(&b.units).unwrap())
-                    },
+                    SortField::Amount => v.sort_by(|a, b| b.amount.total_cmp(&a.amount)),
+                    SortField::Pr => v.sort_by(|a, b| a.price.total_cmp(&b.price)),
+                    SortField::Units => v.sort_by(|a, b| a.units.total_cmp(&b.units)),
                     SortField::Ticker => v.sort_by(|a, b| a.ticker.cmp(&b.ticker)),
                     SortField::Name => v.sort_by(|a, b| a.name.cmp(&b.name)),
                     SortField::Currency => v.sort_by(|a, b| a.currency.cmp(&b.currency)),
@@ -125,14 +250,14 @@ async fn run() -> Result<()> {
                     SortField::Group => v.sort_by(|a, b| a.group.cmp(&b.group)),
                     SortField::Tags => v.sort_by(|a, b| a.tags.cmp(&b.tags)),
                     SortField::Riskyness => v.sort_by(|a, b| a.riskyness.cmp(&b.riskyness)),
-                    SortField::Gain => v.sort_by(|a, b| b.gain.partial_cmp(&a.gain).unwrap()),
+                    SortField::Gain => v.sort_by(|a, b| b.gain.total_cmp(&a.gain)),
                     SortField::Tax => v.sort_by(|a, b| a.tax_status.cmp(&b.tax_status)),
                 }
             } else {
-                // By default sorts by name.
-                v.sort_by(|a, b| a.name.cmp(&b.name))
+                // By default sorts by name, then by account.
+                v.sort_by(|a, b| a.name.cmp(&b.name).then_with(|| a.account.cmp(&b.account)))
             }
-            println!(
+            let header = format!(
                 fmt_portline!(),
                 "ACCOUNT",
                 "%",
@@ -147,30 +272,116 @@ async fn run() -> Result<()> {
                 "PRICE",
                 "AMOUNT",
                 "GAIN",
+                "FX GAIN",
                 "TAX",
+                "ST UNITS",
+                "LT DATE",
                 "ER"
             );
-            v.iter().for_each(|l| println!("{}", l));
-            Ok(())
+            print_all(format, &header, &v)
         }
-        SubCommand::Report { report_type } => {
-            let store = Store::open(home_dir)?;
+        SubCommand::Report {
+            report_type,
+            aggregate,
+            as_of,
+        } => {
+            let store = open(home_dir, &currency, lenient, false)?;
             let rll = store
-                .report(report_type)?
-                .sorted_by(|a, b| b.amount_usd.partial_cmp(&a.amount_usd).unwrap());
-            println!(fmt_report!(), "GROUP", "AMOUNT", "% TOT");
-            rll.for_each(|rl| println!("{}", rl));
-            Ok(())
+                .report(report_type, aggregate, as_of)?
+                .sorted_by(|a, b| b.amount.total_cmp(&a.amount));
+            let header = format!(fmt_report!(), "GROUP", "AMOUNT", "% TOT");
+            print_all(format, &header, &rll.collect::<Vec<_>>())
         }
-        SubCommand::Total {} => {
-            let store = Store::open(home_dir)?;
-            let tot = store.total()?;
-            println!("USD\t{:<10}", tot.sep());
-            Ok(())
+        SubCommand::Total { as_of } => {
+            let store = open(home_dir, &currency, lenient, false)?;
+            let tot = TotalLine {
+                amount: store.total(as_of)?,
+                currency: store.currency.clone(),
+            };
+            print_all(format, "", &[tot])
         }
-        SubCommand::UpdatePrices {} => {
-            let store = Store::open(home_dir)?;
-            store.update_prices().await
+        SubCommand::Performance { as_of } => {
+            let store = open(home_dir, &currency, lenient, false)?;
+            let v = store.performance(as_of)?;
+            let header = format!(
+                fmt_performance!(),
+                "SEGMENT", "PER", "FROM", "TO", "TWR %", "IRR %"
+            );
+            print_all(format, &header, &v)
+        }
+        SubCommand::Gains { year, csv } => {
+            let store = open(home_dir, &currency, lenient, false)?;
+            let v = store.gains(year)?;
+
+            let format = if csv { Format::Csv } else { format };
+            let header = format!(
+                fmt_gain!(),
+                "ACCOUNT",
+                "NAME",
+                "ACQUIRED",
+                "SOLD",
+                "UNITS",
+                "PROCEEDS",
+                "BASIS",
+                "FEES",
+                "WASH",
+                "GAIN",
+                "T"
+            );
+            print_all(format, &header, &v)?;
+
+            // Totals by account, then by term, as a second set of records in every format.
+            let totals = |key: fn(&GainLine) -> &String| {
+                v.iter()
+                    .map(|g| (key(g), g.gain))
+                    .into_group_map()
+                    .into_iter()
+                    .sorted_by_key(|(k, _)| *k)
+                    .map(|(name, gains)| GainTotalLine {
+                        name: name.clone(),
+                        gain: gains.iter().sum(),
+                    })
+                    .collect::<Vec<_>>()
+            };
+            let mut all = totals(|g| &g.account);
+            all.extend(totals(|g| &g.term));
+            let header = format!(concat!("\n", fmt_gain_total!()), "TOTAL", "GAIN");
+            print_all(format, &header, &all)
+        }
+        SubCommand::UpdatePrices {
+            backfill,
+            provider,
+            quotes_dir,
+        } => {
+            let store = open(home_dir, &currency, lenient, true)?;
+            let provider = provider.unwrap_or_default();
+            let updates = if backfill {
+                store
+                    .backfill_prices(provider, quotes_dir.as_deref())
+                    .await?
+            } else {
+                store.update_prices(provider, quotes_dir.as_deref()).await?
+            };
+
+            // Tickers that failed are reported apart from the prices of the others.
+            let mut backfilled = Vec::new();
+            let mut updated = Vec::new();
+            for u in updates {
+                match (u.prices, u.from) {
+                    (Ok(prices), Some(from)) => backfilled.push(BackfillLine {
+                        ticker: u.ticker,
+                        prices: prices.len(),
+                        from,
+                    }),
+                    (Ok(prices), None) => updated.extend(prices),
+                    (Err(e), _) => error!("{}", e),
+                }
+            }
+            if backfill {
+                print_all(format, "", &backfilled)
+            } else {
+                print_all(format, "", &updated)
+            }
         }
     }
 }
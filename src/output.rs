@@ -0,0 +1,124 @@
+use std::{fmt, io, str::FromStr};
+
+use serde::Serialize;
+
+use crate::errors::*;
+
+/// How records are printed.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Format {
+    /// Aligned columns, truncated to fit the terminal
+    #[default]
+    Table,
+    Json,
+    Csv,
+    Tsv,
+}
+
+impl FromStr for Format {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Format> {
+        match &s.to_lowercase()[..] {
+            "table" => Ok(Format::Table),
+            "json" => Ok(Format::Json),
+            "csv" => Ok(Format::Csv),
+            "tsv" => Ok(Format::Tsv),
+            _ => error_chain::bail!("Unknown format {}, use table, json, csv or tsv", s),
+        }
+    }
+}
+
+// Tables and json are written as they are, csv and tsv through a csv writer.
+enum Output<W: io::Write> {
+    Plain(W),
+    Csv(Box<csv::Writer<W>>),
+}
+
+/// Prints records one at a time, as a table or serialized in full, to stdout or any writer.
+/// The first error is kept and returned by `finish`.
+pub struct Printer<W: io::Write = io::Stdout> {
+    format: Format,
+    out: Output<W>,
+    printed: usize,
+    error: Option<Error>,
+}
+
+impl Printer {
+    /// A printer to stdout that starts tables with the given header.
+    pub fn new(format: Format, table_header: &str) -> Printer {
+        Printer::to_writer(io::stdout(), format, table_header)
+    }
+}
+
+impl<W: io::Write> Printer<W> {
+    /// A printer to the writer that starts tables with the given header.
+    pub fn to_writer(mut out: W, format: Format, table_header: &str) -> Printer<W> {
+        let delimiter = match format {
+            Format::Csv => Some(b','),
+            Format::Tsv => Some(b'\t'),
+            _ => None,
+        };
+        let mut error = None;
+        if format == Format::Table && !table_header.is_empty() {
+            error = writeln!(out, "{}", table_header)
+                .chain_err(|| "Error writing the header")
+                .err();
+        }
+        Printer {
+            format,
+            out: match delimiter {
+                Some(d) => Output::Csv(Box::new(
+                    csv::WriterBuilder::new().delimiter(d).from_writer(out),
+                )),
+                None => Output::Plain(out),
+            },
+            printed: 0,
+            error,
+        }
+    }
+
+    pub fn print<T: Serialize + fmt::Display>(&mut self, record: &T) {
+        if self.error.is_some() {
+            return;
+        }
+        let res = match (&mut self.out, self.format) {
+            (Output::Plain(out), Format::Json) => {
+                write!(out, "{}", if self.printed == 0 { "[\n" } else { ",\n" })
+                    .chain_err(|| "Error writing json")
+                    .and_then(|_| {
+                        serde_json::to_writer_pretty(out, record)
+                            .chain_err(|| "Error serializing to json")
+                    })
+            }
+            (Output::Plain(out), _) => {
+                writeln!(out, "{}", record).chain_err(|| "Error writing a line")
+            }
+            (Output::Csv(w), _) => w.serialize(record).chain_err(|| "Error serializing to csv"),
+        };
+        self.printed += 1;
+        self.error = res.err();
+    }
+
+    /// Closes the output, returning the writer or the first error met while printing.
+    pub fn finish(mut self) -> Result<W> {
+        if let Some(e) = self.error.take() {
+            return Err(e);
+        }
+        match self.out {
+            Output::Plain(mut out) => {
+                match self.format {
+                    Format::Json if self.printed == 0 => writeln!(out, "[]"),
+                    Format::Json => writeln!(out, "\n]"),
+                    _ => Ok(()),
+                }
+                .chain_err(|| "Error writing json")?;
+                out.flush().chain_err(|| "Error flushing the output")?;
+                Ok(out)
+            }
+            Output::Csv(w) => w
+                .into_inner()
+                .map_err(|e| Error::from(format!("Error flushing the output: {}", e.error()))),
+        }
+    }
+}
@@ -0,0 +1,159 @@
+use chrono::{DateTime, Datelike, Duration, TimeZone, Utc};
+
+use crate::{Trade, TradeType};
+
+/// Value moved in or out of a position by a trade.
+#[derive(Debug, Clone)]
+pub struct Flow {
+    pub date: DateTime<Utc>,
+    pub account: String,
+    pub stock: String,
+    pub amount: f64,
+}
+
+/// The part of the portfolio a return is calculated for.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Segment {
+    Portfolio,
+    Account(String),
+    Group(String),
+}
+
+impl Segment {
+    pub fn contains(&self, account: &str, group: &str) -> bool {
+        match self {
+            Segment::Portfolio => true,
+            Segment::Account(a) => a == account,
+            Segment::Group(g) => g == group,
+        }
+    }
+
+    pub fn name(&self) -> String {
+        match self {
+            Segment::Portfolio => "Portfolio".to_string(),
+            Segment::Account(a) => format!("Account {}", a),
+            Segment::Group(g) => format!("Group {}", g),
+        }
+    }
+}
+
+/// The value each trade moves in or out of its position and of the account cash, at the trade
+/// price. Buys, sells and dividends move value between positions of the same account, so only
+/// transfers in and out are external flows for the whole portfolio.
+pub fn flows(t: &Trade) -> Vec<Flow> {
+    let amt = t.units * t.price.unwrap_or_default() * t.rate();
+    let flow = |stock: &str, amount: f64| Flow {
+        date: t.date,
+        account: t.account.to_string(),
+        stock: stock.to_string(),
+        amount,
+    };
+    let cash = format!("Cash{}", t.account);
+    let is_cash = t.stock.contains("Cash");
+
+    match t.r#type {
+        TradeType::TrIn => vec![flow(&t.stock, amt)],
+        TradeType::TrOut => vec![flow(&t.stock, -amt)],
+        TradeType::Buy if !is_cash => vec![flow(&t.stock, amt), flow(&cash, -amt)],
+        TradeType::Sell | TradeType::Div if !is_cash => {
+            vec![flow(&t.stock, -amt), flow(&cash, amt)]
+        }
+        _ => Vec::new(),
+    }
+}
+
+/// The last day of the month.
+pub fn month_end(year: i32, month: u32) -> DateTime<Utc> {
+    let (y, m) = if month == 12 {
+        (year + 1, 1)
+    } else {
+        (year, month + 1)
+    };
+    Utc.ymd(y, m, 1).and_hms(0, 0, 0) - Duration::days(1)
+}
+
+/// The month ends strictly between the two dates.
+pub fn month_ends(from: DateTime<Utc>, to: DateTime<Utc>) -> Vec<DateTime<Utc>> {
+    let mut v = Vec::new();
+    let mut d = month_end(from.year(), from.month());
+    while d < to {
+        if d > from {
+            v.push(d);
+        }
+        let next = d + Duration::days(1);
+        d = month_end(next.year(), next.month());
+    }
+    v
+}
+
+fn years(from: DateTime<Utc>, to: DateTime<Utc>) -> f64 {
+    (to - from).num_days() as f64 / 365.0
+}
+
+// Modified Dietz return of a sub-period, weighting each flow by the time it was invested.
+fn dietz(
+    (start, v0): (DateTime<Utc>, f64),
+    (end, v1): (DateTime<Utc>, f64),
+    flows: &[(DateTime<Utc>, f64)],
+) -> Option<f64> {
+    let period = (end - start).num_days() as f64;
+    if period <= 0.0 {
+        return None;
+    }
+    let in_period = flows.iter().filter(|(d, _)| *d > start && *d <= end);
+    let (total, weighted) = in_period.fold((0.0, 0.0), |(t, w), (d, f)| {
+        (t + f, w + f * (end - *d).num_days() as f64 / period)
+    });
+
+    let invested = v0 + weighted;
+    if invested.abs() < 1e-9 {
+        None
+    } else {
+        Some((v1 - v0 - total) / invested)
+    }
+}
+
+/// Time-weighted return, linking the Modified Dietz returns between consecutive valuations.
+pub fn twr(values: &[(DateTime<Utc>, f64)], flows: &[(DateTime<Utc>, f64)]) -> Option<f64> {
+    let returns: Vec<f64> = values
+        .windows(2)
+        .filter_map(|w| dietz(w[0], w[1], flows))
+        .collect();
+    if returns.is_empty() {
+        None
+    } else {
+        Some(returns.iter().fold(1.0, |acc, r| acc * (1.0 + r)) - 1.0)
+    }
+}
+
+/// Annualized internal rate of return of dated cash flows, negative for money invested.
+pub fn xirr(cash_flows: &[(DateTime<Utc>, f64)]) -> Option<f64> {
+    let first = cash_flows.iter().map(|(d, _)| *d).min()?;
+    let npv = |r: f64| -> f64 {
+        cash_flows
+            .iter()
+            .map(|(d, cf)| cf / (1.0 + r).powf(years(first, *d)))
+            .sum()
+    };
+
+    // Bisection: robust, and the range covers any realistic return.
+    let (mut lo, mut hi) = (-0.9999, 100.0);
+    let (mut f_lo, f_hi) = (npv(lo), npv(hi));
+    if !f_lo.is_finite() || !f_hi.is_finite() || f_lo * f_hi > 0.0 {
+        return None;
+    }
+    for _ in 0..200 {
+        let mid = (lo + hi) / 2.0;
+        let f_mid = npv(mid);
+        if f_mid.abs() < 1e-9 {
+            return Some(mid);
+        }
+        if f_lo * f_mid < 0.0 {
+            hi = mid;
+        } else {
+            lo = mid;
+            f_lo = f_mid;
+        }
+    }
+    Some((lo + hi) / 2.0)
+}
@@ -0,0 +1,149 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use chrono::{DateTime, Utc};
+
+use crate::PriceLine;
+
+/// Daily closing prices of every ticker, as stored in the prices file.
+#[derive(Debug, Default)]
+pub struct PriceHistory {
+    prices: HashMap<String, BTreeMap<DateTime<Utc>, f64>>,
+}
+
+/// Currency cross rates go through when a pair is not quoted.
+pub const FX_PIVOT: &str = "USD";
+
+/// The ticker of the rate converting one currency into another.
+pub fn fx_ticker(from: &str, to: &str) -> String {
+    format!("{}{}=X", from, to)
+}
+
+/// The two currencies of an exchange rate ticker.
+pub fn fx_pair(ticker: &str) -> Option<(String, String)> {
+    // Byte slicing needs ASCII, tickers with other characters aren't rates.
+    if ticker.len() == 8 && ticker.is_ascii() && ticker.ends_with("=X") {
+        Some((ticker[0..3].to_string(), ticker[3..6].to_string()))
+    } else {
+        None
+    }
+}
+
+/// Prices are kept one per day, at midnight.
+pub fn day(date: DateTime<Utc>) -> DateTime<Utc> {
+    date.date().and_hms(0, 0, 0)
+}
+
+impl PriceHistory {
+    /// Adds a price, replacing the one for the same ticker and day if there is one.
+    pub fn insert(&mut self, pl: &PriceLine) {
+        self.prices
+            .entry(pl.ticker.clone())
+            .or_default()
+            .insert(day(pl.date), pl.price);
+    }
+
+    /// True if the history already has this price for the ticker on that day.
+    pub fn contains(&self, pl: &PriceLine) -> bool {
+        self.prices
+            .get(&pl.ticker)
+            .and_then(|h| h.get(&day(pl.date)))
+            .is_some_and(|p| *p == pl.price)
+    }
+
+    /// The closest price on or before `date`.
+    pub fn at(&self, ticker: &str, date: DateTime<Utc>) -> Option<PriceLine> {
+        self.prices
+            .get(ticker)?
+            .range(..=date)
+            .next_back()
+            .map(|(d, p)| PriceLine {
+                ticker: ticker.to_string(),
+                price: *p,
+                date: *d,
+            })
+    }
+
+    pub fn latest(&self, ticker: &str) -> Option<PriceLine> {
+        self.prices
+            .get(ticker)?
+            .iter()
+            .next_back()
+            .map(|(d, p)| PriceLine {
+                ticker: ticker.to_string(),
+                price: *p,
+                date: *d,
+            })
+    }
+
+    /// The closest price on or before `date`, the latest one without a date.
+    pub fn price(&self, ticker: &str, date: Option<DateTime<Utc>>) -> Option<PriceLine> {
+        match date {
+            Some(d) => self.at(ticker, d),
+            None => self.latest(ticker),
+        }
+    }
+
+    /// The rate converting `from` into `to`, quoted directly, as the inverse of the opposite
+    /// pair or crossed through another currency. Its date is the one of the oldest rate used.
+    pub fn rate(&self, from: &str, to: &str, date: Option<DateTime<Utc>>) -> Option<PriceLine> {
+        let rate = |price: f64, date: DateTime<Utc>| PriceLine {
+            ticker: fx_ticker(from, to),
+            price,
+            date,
+        };
+        if from == to {
+            return Some(rate(1.0, date.unwrap_or_else(Utc::now)));
+        }
+        if let Some(r) = self.pair(from, to, date) {
+            return Some(r);
+        }
+
+        let quoted: BTreeSet<String> = self
+            .prices
+            .keys()
+            .filter_map(|t| fx_pair(t))
+            .flat_map(|(a, b)| vec![a, b])
+            .collect();
+        let pivots = std::iter::once(FX_PIVOT.to_string()).chain(quoted);
+        pivots.filter(|p| p != from && p != to).find_map(|p| {
+            let a = self.pair(from, &p, date)?;
+            let b = self.pair(&p, to, date)?;
+            Some(rate(a.price * b.price, a.date.min(b.date)))
+        })
+    }
+
+    // The rate of a pair quoted in either direction.
+    fn pair(&self, from: &str, to: &str, date: Option<DateTime<Utc>>) -> Option<PriceLine> {
+        self.price(&fx_ticker(from, to), date).or_else(|| {
+            self.price(&fx_ticker(to, from), date)
+                .filter(|p| p.price != 0.0)
+                .map(|p| PriceLine {
+                    ticker: fx_ticker(from, to),
+                    price: 1.0 / p.price,
+                    date: p.date,
+                })
+        })
+    }
+
+    /// The prices of the ticker from `from` onwards.
+    pub fn range(&self, ticker: &str, from: DateTime<Utc>) -> Vec<PriceLine> {
+        self.prices.get(ticker).map_or(Vec::new(), |h| {
+            h.range(day(from)..)
+                .map(|(d, p)| PriceLine {
+                    ticker: ticker.to_string(),
+                    price: *p,
+                    date: *d,
+                })
+                .collect()
+        })
+    }
+
+    /// The date of the most recent price for the ticker.
+    pub fn last_date(&self, ticker: &str) -> Option<DateTime<Utc>> {
+        self.prices.get(ticker)?.keys().next_back().copied()
+    }
+
+    pub fn tickers(&self) -> impl Iterator<Item = &String> {
+        self.prices.keys()
+    }
+}
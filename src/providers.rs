@@ -0,0 +1,136 @@
+use std::{fs, path, str::FromStr};
+
+use chrono::{DateTime, Utc};
+use futures::future::{FutureExt, LocalBoxFuture};
+use serde::{Deserialize, Serialize};
+use yahoo_finance::{history, Interval, Timestamped};
+
+use crate::errors::*;
+use crate::prices::PriceHistory;
+use crate::{tsv, PriceLine};
+
+/// Date and closing price of a ticker.
+pub type Quote = (DateTime<Utc>, f64);
+
+/// A source of prices for tickers.
+pub trait PriceProvider {
+    /// The latest closing price for the ticker.
+    fn quote<'a>(&'a self, ticker: &'a str) -> LocalBoxFuture<'a, Result<Quote>>;
+
+    /// The daily closing prices for the ticker from the given date to today.
+    fn history<'a>(
+        &'a self,
+        ticker: &'a str,
+        from: DateTime<Utc>,
+    ) -> LocalBoxFuture<'a, Result<Vec<Quote>>>;
+}
+
+/// Which provider a stock gets its prices from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default, Deserialize, Serialize)]
+pub enum ProviderKind {
+    #[default]
+    Yahoo,
+    File,
+}
+
+impl FromStr for ProviderKind {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<ProviderKind> {
+        match &s.to_lowercase()[..] {
+            "yahoo" => Ok(ProviderKind::Yahoo),
+            "file" => Ok(ProviderKind::File),
+            _ => error_chain::bail!("Unknown price provider {}, use Yahoo or File", s),
+        }
+    }
+}
+
+/// Prices downloaded from the Yahoo finance API.
+pub struct Yahoo;
+
+impl PriceProvider for Yahoo {
+    fn quote<'a>(&'a self, ticker: &'a str) -> LocalBoxFuture<'a, Result<Quote>> {
+        async move {
+            let bars = history::retrieve_interval(ticker, Interval::_5d)
+                .await
+                .chain_err(|| format!("Error retrieving prices for {}", ticker))?;
+            bars.last()
+                .map(|b| (b.datetime(), b.close))
+                .ok_or_else(|| Error::from(format!("Empty prices returned for {}", ticker)))
+        }
+        .boxed_local()
+    }
+
+    fn history<'a>(
+        &'a self,
+        ticker: &'a str,
+        from: DateTime<Utc>,
+    ) -> LocalBoxFuture<'a, Result<Vec<Quote>>> {
+        async move {
+            let bars = history::retrieve_range(ticker, from, None)
+                .await
+                .chain_err(|| format!("Error retrieving prices for {}", ticker))?;
+            Ok(bars.iter().map(|b| (b.datetime(), b.close)).collect())
+        }
+        .boxed_local()
+    }
+}
+
+/// Prices read from the .csv and .tsv files dropped in a directory.
+/// The files have the same columns as the prices file.
+pub struct QuoteFiles {
+    quotes: PriceHistory,
+}
+
+impl QuoteFiles {
+    pub fn open(dir: &path::Path) -> Result<QuoteFiles> {
+        let mut quotes = PriceHistory::default();
+        let entries = fs::read_dir(dir)
+            .chain_err(|| format!("Cannot open quotes directory {}", dir.to_string_lossy()))?;
+
+        for entry in entries {
+            let file = entry.chain_err(|| "Cannot read quotes directory")?.path();
+            let delimiter = match file.extension().and_then(|e| e.to_str()) {
+                Some("tsv") => b'\t',
+                Some("csv") => b',',
+                _ => continue,
+            };
+            let mut rdr = tsv::reader()
+                .delimiter(delimiter)
+                .from_path(&file)
+                .chain_err(|| format!("Cannot open quotes file {}", file.to_string_lossy()))?;
+
+            let name = file.to_string_lossy();
+            for (_, row) in tsv::read_rows(&mut rdr, &name)? {
+                let pl: PriceLine = row?;
+                quotes.insert(&pl);
+            }
+        }
+        Ok(QuoteFiles { quotes })
+    }
+}
+
+impl PriceProvider for QuoteFiles {
+    fn quote<'a>(&'a self, ticker: &'a str) -> LocalBoxFuture<'a, Result<Quote>> {
+        let q = self
+            .quotes
+            .latest(ticker)
+            .map(|pl| (pl.date, pl.price))
+            .ok_or_else(|| Error::from(format!("No quote file has prices for {}", ticker)));
+        futures::future::ready(q).boxed_local()
+    }
+
+    fn history<'a>(
+        &'a self,
+        ticker: &'a str,
+        from: DateTime<Utc>,
+    ) -> LocalBoxFuture<'a, Result<Vec<Quote>>> {
+        let h = self
+            .quotes
+            .range(ticker, from)
+            .into_iter()
+            .map(|pl| (pl.date, pl.price))
+            .collect();
+        futures::future::ready(Ok(h)).boxed_local()
+    }
+}
@@ -0,0 +1,380 @@
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::{fmt, fs};
+
+use csv::StringRecord;
+use itertools::Itertools;
+
+use crate::errors::*;
+use crate::files;
+use crate::tsv::{self, Table};
+
+/// Database the sqlite storage keeps the tables in, in the portfolio directory.
+pub const DB_FILE: &str = "lupo.db";
+
+/// Where the portfolio tables are kept. Tables are named after the files of the tsv layout,
+/// `trades.tsv` and so on, whatever the storage, and exchanged as tab separated text so
+/// that the journal and the editor work the same with any of them.
+pub trait Storage {
+    /// How rows of the table are located in errors, the file name for the tsv storage.
+    fn location(&self, table: &str) -> String;
+
+    fn exists(&self, table: &str) -> Result<bool>;
+
+    /// The rows of the table, with the line or row each was read from. None if there is
+    /// no such table.
+    fn read(&self, table: &str) -> Result<Option<Table>>;
+
+    /// The table as tab separated text, None if there is no such table.
+    fn export(&self, table: &str) -> Result<Option<Vec<u8>>>;
+
+    /// Replaces the table, or creates it, with the tab separated text.
+    fn import(&self, table: &str, text: &[u8]) -> Result<()>;
+
+    /// Adds the rows of the tab separated text, header first, at the end of the table,
+    /// creating it if there is none. The header must be the one of the table.
+    fn append(&self, table: &str, text: &[u8]) -> Result<()>;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum StorageKind {
+    /// A tab separated file per table, the layout `lupo init` creates.
+    #[default]
+    Tsv,
+    /// All the tables in one sqlite database.
+    Sqlite,
+}
+
+impl FromStr for StorageKind {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<StorageKind> {
+        match &s.to_lowercase()[..] {
+            "tsv" => Ok(StorageKind::Tsv),
+            "sqlite" => Ok(StorageKind::Sqlite),
+            _ => error_chain::bail!("Unknown storage {}, use tsv or sqlite", s),
+        }
+    }
+}
+
+impl fmt::Display for StorageKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StorageKind::Tsv => write!(f, "tsv"),
+            StorageKind::Sqlite => write!(f, "sqlite"),
+        }
+    }
+}
+
+/// The storage of the kind for the portfolio in the directory.
+pub fn open(kind: StorageKind, home_dir: &Path) -> Result<Box<dyn Storage>> {
+    Ok(match kind {
+        StorageKind::Tsv => Box::new(TsvStorage::new(home_dir)),
+        StorageKind::Sqlite => Box::new(SqliteStorage::open(&home_dir.join(DB_FILE))?),
+    })
+}
+
+// Rows are appended to a table only if they have its columns.
+fn check_headers(table: &str, headers: &StringRecord, appended: &StringRecord) -> Result<()> {
+    if headers != appended {
+        error_chain::bail!(
+            "Cannot add rows to {}, its columns are {} rather than {}",
+            table,
+            headers.iter().join(", "),
+            appended.iter().join(", ")
+        );
+    }
+    Ok(())
+}
+
+/// A tab separated file per table. Files are written whole through a temporary file.
+pub struct TsvStorage {
+    dir: PathBuf,
+}
+
+impl TsvStorage {
+    pub fn new(dir: &Path) -> TsvStorage {
+        TsvStorage {
+            dir: dir.to_path_buf(),
+        }
+    }
+}
+
+impl Storage for TsvStorage {
+    fn location(&self, table: &str) -> String {
+        table.to_string()
+    }
+
+    fn exists(&self, table: &str) -> Result<bool> {
+        Ok(self.dir.join(table).exists())
+    }
+
+    fn read(&self, table: &str) -> Result<Option<Table>> {
+        let path = self.dir.join(table);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let mut rdr = tsv::reader()
+            .from_path(path)
+            .chain_err(|| format!("Cannot open {}", table))?;
+        tsv::read_table(&mut rdr, table).map(Some)
+    }
+
+    fn export(&self, table: &str) -> Result<Option<Vec<u8>>> {
+        let path = self.dir.join(table);
+        if !path.exists() {
+            return Ok(None);
+        }
+        fs::read(path)
+            .map(Some)
+            .chain_err(|| format!("Cannot read {}", table))
+    }
+
+    fn import(&self, table: &str, text: &[u8]) -> Result<()> {
+        files::write_atomic(&self.dir.join(table), text)
+    }
+
+    fn append(&self, table: &str, text: &[u8]) -> Result<()> {
+        let mut contents = match self.read(table)? {
+            Some(t) => {
+                check_headers(table, &t.headers, &tsv::parse(text, table)?.headers)?;
+                self.export(table)?.unwrap_or_default()
+            }
+            None => Vec::new(),
+        };
+        // The rows are added as they are written, without the header if there is one.
+        let text = if contents.is_empty() {
+            text
+        } else {
+            text.iter()
+                .position(|c| *c == b'\n')
+                .map_or(&[][..], |i| &text[i + 1..])
+        };
+        // The last line of a file edited by hand may have no new line.
+        if contents.last().is_some_and(|c| *c != b'\n') {
+            contents.push(b'\n');
+        }
+        contents.extend_from_slice(text);
+        self.import(table, &contents)
+    }
+}
+
+/// A table per portfolio table in a sqlite database, with a text column per column of the
+/// tsv layout. Rows are numbered in the order they were added, as lines are in files.
+/// The comments of the tables are kept apart, with the number of rows before each.
+pub struct SqliteStorage {
+    conn: rusqlite::Connection,
+}
+
+// The table of the comments of all the tables.
+const COMMENTS: &str = "_comments";
+
+// Table names drop the extension of the files they replace.
+fn sql_name(table: &str) -> String {
+    let name = table.strip_suffix(".tsv").unwrap_or(table);
+    format!("\"{}\"", name.replace('"', "\"\""))
+}
+
+impl SqliteStorage {
+    pub fn open(path: &Path) -> Result<SqliteStorage> {
+        let conn = rusqlite::Connection::open(path)
+            .chain_err(|| format!("Cannot open database {}", path.to_string_lossy()))?;
+        Ok(SqliteStorage { conn })
+    }
+
+    fn create_comments(&self) -> Result<()> {
+        self.conn
+            .execute(
+                &format!(
+                    "CREATE TABLE IF NOT EXISTS {} (\"table\" TEXT, rows INTEGER, text TEXT)",
+                    COMMENTS
+                ),
+                [],
+            )
+            .chain_err(|| format!("Cannot create table {}", self.location(COMMENTS)))?;
+        Ok(())
+    }
+
+    // The comments of the table, with the number of records before each, header included.
+    fn comments(&self, table: &str) -> Result<Vec<(u64, String)>> {
+        if !self.exists(COMMENTS)? {
+            return Ok(Vec::new());
+        }
+        let error = || format!("Cannot read the comments of {}", self.location(table));
+        let mut stmt = self
+            .conn
+            .prepare(&format!(
+                "SELECT rows, text FROM {} WHERE \"table\" = ? ORDER BY rowid",
+                COMMENTS
+            ))
+            .chain_err(error)?;
+        let comments = stmt
+            .query_map([table], |r| Ok((r.get::<_, i64>(0)? as u64, r.get(1)?)))
+            .chain_err(error)?
+            .collect::<rusqlite::Result<Vec<_>>>()
+            .chain_err(error)?;
+        Ok(comments)
+    }
+
+    fn create(&self, table: &str, headers: &StringRecord) -> Result<()> {
+        let columns = headers
+            .iter()
+            .map(|h| format!("{} TEXT", sql_name(h)))
+            .collect::<Vec<_>>()
+            .join(", ");
+        self.conn
+            .execute(
+                &format!(
+                    "CREATE TABLE IF NOT EXISTS {} ({})",
+                    sql_name(table),
+                    columns
+                ),
+                [],
+            )
+            .chain_err(|| format!("Cannot create table {}", self.location(table)))?;
+        Ok(())
+    }
+
+    fn insert(&self, table: &str, headers: &StringRecord, rows: &[StringRecord]) -> Result<()> {
+        let sql = format!(
+            "INSERT INTO {} ({}) VALUES ({})",
+            sql_name(table),
+            headers.iter().map(sql_name).collect::<Vec<_>>().join(", "),
+            vec!["?"; headers.len()].join(", ")
+        );
+        let mut stmt = self
+            .conn
+            .prepare(&sql)
+            .chain_err(|| format!("Cannot add rows to {}", self.location(table)))?;
+        for row in rows {
+            // Rows of a flexible file may have fewer or more fields than the header.
+            let values = (0..headers.len()).map(|i| row.get(i).unwrap_or(""));
+            stmt.execute(rusqlite::params_from_iter(values))
+                .chain_err(|| format!("Cannot add a row to {}", self.location(table)))?;
+        }
+        Ok(())
+    }
+}
+
+impl Storage for SqliteStorage {
+    fn location(&self, table: &str) -> String {
+        format!(
+            "{}:{}",
+            DB_FILE,
+            table.strip_suffix(".tsv").unwrap_or(table)
+        )
+    }
+
+    fn exists(&self, table: &str) -> Result<bool> {
+        let name = table.strip_suffix(".tsv").unwrap_or(table);
+        self.conn
+            .query_row(
+                "SELECT count(*) FROM sqlite_master WHERE type = 'table' AND name = ?",
+                [name],
+                |r| r.get::<_, i64>(0),
+            )
+            .map(|n| n > 0)
+            .chain_err(|| format!("Cannot read database {}", DB_FILE))
+    }
+
+    fn read(&self, table: &str) -> Result<Option<Table>> {
+        if !self.exists(table)? {
+            return Ok(None);
+        }
+        let error = || format!("Cannot read {}", self.location(table));
+        let mut stmt = self
+            .conn
+            .prepare(&format!(
+                "SELECT rowid, * FROM {} ORDER BY rowid",
+                sql_name(table)
+            ))
+            .chain_err(error)?;
+        let headers: StringRecord = stmt.column_names().into_iter().skip(1).collect();
+        let columns = headers.len();
+        let rows = stmt
+            .query_map([], |r| {
+                let mut record = StringRecord::new();
+                for i in 1..=columns {
+                    record.push_field(&r.get::<_, Option<String>>(i)?.unwrap_or_default());
+                }
+                Ok((r.get::<_, i64>(0)? as u64, record))
+            })
+            .chain_err(error)?
+            .collect::<rusqlite::Result<Vec<_>>>()
+            .chain_err(error)?;
+        Ok(Some(Table { headers, rows }))
+    }
+
+    fn export(&self, table: &str) -> Result<Option<Vec<u8>>> {
+        let t = match self.read(table)? {
+            Some(t) => t,
+            None => return Ok(None),
+        };
+        let mut comments = self.comments(table)?.into_iter().peekable();
+        let mut text = Vec::new();
+        let records = std::iter::once(&t.headers).chain(t.rows.iter().map(|(_, r)| r));
+        for (i, record) in records.enumerate() {
+            while let Some((_, c)) = comments.next_if(|(rows, _)| *rows <= i as u64) {
+                text.extend_from_slice(c.as_bytes());
+                text.push(b'\n');
+            }
+            text.extend(tsv::render_records(std::iter::once(record))?);
+        }
+        for (_, c) in comments {
+            text.extend_from_slice(c.as_bytes());
+            text.push(b'\n');
+        }
+        Ok(Some(text))
+    }
+
+    fn import(&self, table: &str, text: &[u8]) -> Result<()> {
+        let parsed = tsv::parse(text, table)?;
+        let tx = self
+            .conn
+            .unchecked_transaction()
+            .chain_err(|| format!("Cannot write database {}", DB_FILE))?;
+        tx.execute(&format!("DROP TABLE IF EXISTS {}", sql_name(table)), [])
+            .chain_err(|| format!("Cannot replace {}", self.location(table)))?;
+        self.create(table, &parsed.headers)?;
+        let comments = tsv::comments(text, &parsed);
+        let rows: Vec<StringRecord> = parsed.rows.into_iter().map(|(_, r)| r).collect();
+        self.insert(table, &parsed.headers, &rows)?;
+
+        self.create_comments()?;
+        let error = || format!("Cannot write the comments of {}", self.location(table));
+        tx.execute(
+            &format!("DELETE FROM {} WHERE \"table\" = ?", COMMENTS),
+            [table],
+        )
+        .chain_err(error)?;
+        for (rows, c) in comments {
+            tx.execute(
+                &format!(
+                    "INSERT INTO {} (\"table\", rows, text) VALUES (?, ?, ?)",
+                    COMMENTS
+                ),
+                rusqlite::params![table, rows as i64, c],
+            )
+            .chain_err(error)?;
+        }
+        tx.commit()
+            .chain_err(|| format!("Cannot write database {}", DB_FILE))
+    }
+
+    fn append(&self, table: &str, text: &[u8]) -> Result<()> {
+        let parsed = tsv::parse(text, table)?;
+        if let Some(t) = self.read(table)? {
+            check_headers(table, &t.headers, &parsed.headers)?;
+        }
+        let headers = &parsed.headers;
+        let rows: Vec<StringRecord> = parsed.rows.into_iter().map(|(_, r)| r).collect();
+        let tx = self
+            .conn
+            .unchecked_transaction()
+            .chain_err(|| format!("Cannot write database {}", DB_FILE))?;
+        self.create(table, headers)?;
+        self.insert(table, headers, &rows)?;
+        tx.commit()
+            .chain_err(|| format!("Cannot write database {}", DB_FILE))
+    }
+}
@@ -0,0 +1,210 @@
+use std::io;
+
+use csv::{DeserializeErrorKind, StringRecord};
+use itertools::Itertools;
+use serde::de::DeserializeOwned;
+
+use crate::errors::*;
+
+/// The rows of a table with the line, or row, each starts at, and the columns they have.
+#[derive(Debug, Clone, Default)]
+pub struct Table {
+    pub headers: StringRecord,
+    pub rows: Vec<(u64, StringRecord)>,
+}
+
+/// How the portfolio files are read: tab separated, with `#` comments.
+pub fn reader() -> csv::ReaderBuilder {
+    let mut builder = csv::ReaderBuilder::new();
+    builder
+        .delimiter(b'\t')
+        .flexible(true)
+        .trim(csv::Trim::All)
+        .comment(Some(b'#'));
+    builder
+}
+
+/// Reads a whole table, failing at the first row that isn't well formed.
+pub fn read_table<R: io::Read>(rdr: &mut csv::Reader<R>, file: &str) -> Result<Table> {
+    let headers = rdr
+        .headers()
+        .chain_err(|| format!("{}:1: Can't read the header", file))?
+        .clone();
+    let mut rows = Vec::new();
+    for record in rdr.records() {
+        match record {
+            Ok(record) => rows.push((record.position().map_or(0, |p| p.line()), record)),
+            Err(e) => {
+                let line = e.position().map_or(0, |p| p.line());
+                return Err(e).chain_err(|| format!("{}:{}: Csv not well formed", file, line));
+            }
+        }
+    }
+    Ok(Table { headers, rows })
+}
+
+/// Tab separated text as a table.
+pub fn parse(text: &[u8], file: &str) -> Result<Table> {
+    read_table(&mut reader().from_reader(text), file)
+}
+
+/// The `#` comment lines of the text the table was parsed from, with the number of records
+/// before each, the header included, so that they can be put back where they were.
+pub fn comments(text: &[u8], table: &Table) -> Vec<(u64, String)> {
+    let mut header = 0;
+    let mut comments = Vec::new();
+    for (i, l) in text.split(|b| *b == b'\n').enumerate() {
+        let line = i as u64 + 1;
+        if l.starts_with(b"#") {
+            let rows = table.rows.iter().take_while(|(n, _)| *n < line).count() as u64;
+            let comment = String::from_utf8_lossy(l);
+            comments.push((header + rows, comment.trim_end_matches('\r').to_string()));
+        } else if !l.is_empty() && l != b"\r" {
+            header = 1;
+        }
+    }
+    comments
+}
+
+/// Whether the texts have the same header and rows, however they are quoted. Rows are
+/// compared on the columns of the header, as a storage may drop the fields past them.
+pub fn same_rows(a: &[u8], b: &[u8]) -> bool {
+    let (ta, tb) = match (parse(a, ""), parse(b, "")) {
+        (Ok(ta), Ok(tb)) => (ta, tb),
+        _ => return a == b,
+    };
+    let fields = |r: &StringRecord| {
+        (0..ta.headers.len())
+            .map(|i| r.get(i).unwrap_or("").to_string())
+            .collect::<Vec<_>>()
+    };
+    ta.headers == tb.headers
+        && ta.rows.len() == tb.rows.len()
+        && ta
+            .rows
+            .iter()
+            .zip(&tb.rows)
+            .all(|((_, ra), (_, rb))| fields(ra) == fields(rb))
+}
+
+/// The table as tab separated text, header first.
+pub fn render(table: &Table) -> Result<Vec<u8>> {
+    render_records(std::iter::once(&table.headers).chain(table.rows.iter().map(|(_, r)| r)))
+}
+
+/// The records as tab separated lines.
+pub fn render_records<'a>(records: impl Iterator<Item = &'a StringRecord>) -> Result<Vec<u8>> {
+    let mut wtr = csv::WriterBuilder::new()
+        .delimiter(b'\t')
+        .flexible(true)
+        .from_writer(Vec::new());
+    for record in records {
+        wtr.write_record(record)
+            .chain_err(|| "Error serializing a row")?;
+    }
+    wtr.into_inner().chain_err(|| "Error serializing a table")
+}
+
+/// The rows of a table deserialized, or why each can't be.
+/// Errors name the file, the line, the value and the column when it is known.
+pub fn rows<T: DeserializeOwned>(table: &Table, file: &str) -> Vec<(u64, Result<T>)> {
+    table
+        .rows
+        .iter()
+        .map(|(line, record)| {
+            let row = record
+                .deserialize(Some(&table.headers))
+                .map_err(|e| row_error(file, *line, &table.headers, record, e));
+            (*line, row)
+        })
+        .collect()
+}
+
+/// The rows of a file with the line each starts at, or why the row can't be read.
+/// Errors name the file, the line, the value and the column when it is known.
+pub fn read_rows<T: DeserializeOwned, R: io::Read>(
+    rdr: &mut csv::Reader<R>,
+    file: &str,
+) -> Result<Vec<(u64, Result<T>)>> {
+    Ok(rows(&read_table(rdr, file)?, file))
+}
+
+// The value serde quotes in its messages, `like this` or "like this".
+fn quoted(m: &str) -> Option<&str> {
+    let (open, close) = match m.find(['`', '"'])? {
+        i if m[i..].starts_with('`') => (i, '`'),
+        i => (i, '"'),
+    };
+    let rest = &m[open + 1..];
+    rest.find(close).map(|end| &rest[..end])
+}
+
+// What was expected, from the message serde gives for a value it can't deserialize.
+fn expected(kind: &DeserializeErrorKind) -> String {
+    match kind {
+        DeserializeErrorKind::Message(m) if m.starts_with("unknown variant") => {
+            let variants = m.split("expected one of ").nth(1).unwrap_or(m);
+            format!("is not one of {}", variants.replace('`', ""))
+        }
+        DeserializeErrorKind::Message(m) if m.contains(", expected ") => {
+            format!("is not {}", m.split(", expected ").nth(1).unwrap_or(m))
+        }
+        DeserializeErrorKind::Message(m) | DeserializeErrorKind::Unsupported(m) => m.clone(),
+        DeserializeErrorKind::UnexpectedEndOfRow => "is missing".to_string(),
+        DeserializeErrorKind::InvalidUtf8(_) => "is not valid UTF-8".to_string(),
+        DeserializeErrorKind::ParseBool(_) => "is not true or false".to_string(),
+        DeserializeErrorKind::ParseInt(_) => "is not a whole number".to_string(),
+        DeserializeErrorKind::ParseFloat(_) => "is not a number".to_string(),
+    }
+}
+
+fn row_error(
+    file: &str,
+    line: u64,
+    headers: &StringRecord,
+    record: &StringRecord,
+    e: csv::Error,
+) -> Error {
+    let message = match e.kind() {
+        csv::ErrorKind::Deserialize { err, .. } => match err.kind() {
+            DeserializeErrorKind::Message(m) if m.starts_with("missing field") => {
+                format!("{}:{}: {}", file, line, m.replace('`', ""))
+            }
+            kind => {
+                // Errors raised by serde rather than csv don't know their column. It is the
+                // one holding the value they quote, when no other column holds it too.
+                let column = err.field().map(|i| i as usize).or_else(|| match kind {
+                    DeserializeErrorKind::Message(m) => {
+                        let value = quoted(m)?;
+                        let mut found = record.iter().positions(|v| v == value);
+                        match (found.next(), found.next()) {
+                            (Some(i), None) => Some(i),
+                            _ => None,
+                        }
+                    }
+                    _ => None,
+                });
+                match (column, kind) {
+                    (Some(i), _) => format!(
+                        "{}:{} column {}: '{}' {}",
+                        file,
+                        line,
+                        headers.get(i).unwrap_or("?"),
+                        record.get(i).unwrap_or(""),
+                        expected(kind)
+                    ),
+                    (None, DeserializeErrorKind::Message(m)) if quoted(m).is_some() => format!(
+                        "{}:{}: '{}' {}",
+                        file,
+                        line,
+                        quoted(m).unwrap_or(""),
+                        expected(kind)
+                    ),
+                    (None, _) => format!("{}:{}: {}", file, line, expected(kind)),
+                }
+            }
+        },
+        _ => format!("{}:{}: Csv not well formed", file, line),
+    };
+    Error::with_chain(e, message)
+}
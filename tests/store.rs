@@ -1,3 +1,5 @@
+#![allow(clippy::bool_assert_comparison, clippy::ineffective_open_options)]
+
 use lupo::errors::*;
 use std::fs::OpenOptions;
 use std::io::prelude::*;
@@ -82,3 +84,1494 @@ fn check_err_if_invalid_trade() -> Result<()> {
     assert_eq!(true, r.is_err());
     Ok(())
 }
+
+fn append_lines(path: &std::path::Path, lines: &[&str]) -> Result<()> {
+    let mut file = OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(path)
+        .chain_err(|| "Can't open file")?;
+    for l in lines {
+        writeln!(file, "{}", l).chain_err(|| "Can't print to file")?;
+    }
+    Ok(())
+}
+
+// Two accounts holding the same ETF, with prices for it and for the dollar.
+fn two_accounts_portfolio(home: &std::path::Path) -> Result<()> {
+    append_lines(
+        &home.join("stocks.tsv"),
+        &[
+            "CashIB	Cash	Cash	Cash	A		USD	USD",
+            "CashFid	Cash	Cash	Cash	A		USD	USD",
+            "Vanguard FTSE	Stock	Equity	World	C	VWRL	USD	USD",
+        ],
+    )?;
+    append_lines(
+        &home.join("trades.tsv"),
+        &[
+            "IB	2015/04/27	TrIn	CashIB	10000	1	0	1	1",
+            "Fid	2015/04/27	TrIn	CashFid	10000	1	0	1	1",
+            "IB	2016/01/04	Buy	Vanguard FTSE	10	100	1	1	1",
+            "Fid	2016/02/04	Buy	Vanguard FTSE	20	90	1	1	1",
+        ],
+    )?;
+    append_lines(
+        &home.join("prices.tsv"),
+        &[
+            "ticker	price	date",
+            "VWRL	110	2016/03/01",
+            "USDUSD=X	1	2016/03/01",
+        ],
+    )
+}
+
+#[test]
+fn port_keeps_positions_per_account() -> Result<()> {
+    temp_store!(store, home, false);
+    two_accounts_portfolio(home.path())?;
+
+    let port = store.port(false, true, false, None)?;
+    let vwrl: Vec<_> = port.iter().filter(|l| l.name == "Vanguard FTSE").collect();
+    assert_eq!(2, vwrl.len());
+
+    let ib = vwrl.iter().find(|l| l.account == "IB").unwrap();
+    assert_eq!(10.0, ib.units);
+    assert_eq!(1000.0, ib.cost);
+
+    let fid = vwrl.iter().find(|l| l.account == "Fid").unwrap();
+    assert_eq!(20.0, fid.units);
+    assert_eq!(1800.0, fid.cost);
+
+    let cash_ib = port.iter().find(|l| l.name == "CashIB").unwrap();
+    assert_eq!(9000.0, cash_ib.units);
+    Ok(())
+}
+
+#[test]
+fn port_aggregates_positions_across_accounts() -> Result<()> {
+    temp_store!(store, home, false);
+    two_accounts_portfolio(home.path())?;
+
+    let port = store.port(false, true, true, None)?;
+    let vwrl: Vec<_> = port.iter().filter(|l| l.name == "Vanguard FTSE").collect();
+    assert_eq!(1, vwrl.len());
+    assert_eq!("Fid,IB", vwrl[0].account);
+    assert_eq!(30.0, vwrl[0].units);
+    assert_eq!(2800.0, vwrl[0].cost);
+    assert_eq!(3300.0, vwrl[0].amount);
+
+    // The wash sale of one account shows on the merged line.
+    append_lines(
+        &home.path().join("trades.tsv"),
+        &["IB	2016/01/05	Sell	Vanguard FTSE	5	80	0	1	1"],
+    )?;
+    let store = lupo::Store::open(home.path())?;
+    let port = store.port(false, true, true, None)?;
+    let vwrl = port.iter().find(|l| l.name == "Vanguard FTSE").unwrap();
+    assert!(vwrl.error.contains("WS"));
+    Ok(())
+}
+
+fn two_lots_portfolio(home: &std::path::Path, method: &str) -> Result<()> {
+    append_lines(&home.join("accounts.tsv"), &[&format!("IB	{}", method)])?;
+    append_lines(
+        &home.join("stocks.tsv"),
+        &[
+            "CashIB	Cash	Cash	Cash	A		USD	USD",
+            "Vanguard FTSE	Stock	Equity	World	C	VWRL	USD	USD",
+        ],
+    )?;
+    append_lines(
+        &home.join("trades.tsv"),
+        &[
+            "IB	2015/04/27	TrIn	CashIB	10000	1	0	1	1",
+            "IB	2016/01/04	Buy	Vanguard FTSE	10	100	0	1	1",
+            "IB	2016/06/04	Buy	Vanguard FTSE	10	50	0	1	1",
+            "IB	2017/01/04	Sell	Vanguard FTSE	5	120	2	1	1	2016/06/04",
+        ],
+    )
+}
+
+#[test]
+fn lots_are_matched_with_the_account_method() -> Result<()> {
+    let cases = [
+        ("Fifo", 500.0),
+        ("Lifo", 250.0),
+        ("Average", 375.0),
+        ("Specific", 250.0),
+    ];
+    for (method, basis) in cases.iter() {
+        temp_store!(store, home, false);
+        two_lots_portfolio(home.path(), method)?;
+
+        let book = store.lots()?;
+        assert_eq!(1, book.sales.len());
+        let sale = &book.sales[0];
+        assert_eq!(*basis, sale.basis(), "{}", method);
+        assert_eq!(600.0 - 2.0 - basis, sale.gain(), "{}", method);
+
+        let open: f64 = book
+            .open_lots("IB", "Vanguard FTSE")
+            .iter()
+            .map(|l| l.basis)
+            .sum();
+        assert_eq!(1500.0 - basis, open, "{}", method);
+    }
+    Ok(())
+}
+
+#[test]
+fn overselling_or_selling_a_lot_that_is_not_open_is_an_error() -> Result<()> {
+    let cases = [
+        (
+            "Fifo",
+            "IB	2017/02/01	Sell	Vanguard FTSE	20	120	0	1	1",
+            "IB Vanguard FTSE: sold 20 units but only 15 were held",
+        ),
+        (
+            "Specific",
+            "IB	2017/02/01	Sell	Vanguard FTSE	1	120	0	1	1	2015/01/01",
+            "IB Vanguard FTSE: no open lot acquired on 2015/01/01",
+        ),
+    ];
+    for (method, sell, cause) in cases.iter() {
+        temp_store!(store, home, false);
+        two_lots_portfolio(home.path(), method)?;
+        append_lines(&home.path().join("trades.tsv"), &[sell])?;
+
+        let e = store.lots().unwrap_err();
+        assert_eq!("trades.tsv:6: Can't match the trade to lots", e.to_string());
+        assert_eq!(*cause, e.iter().nth(1).unwrap().to_string());
+    }
+    Ok(())
+}
+
+#[test]
+fn transfers_move_lots_between_accounts_without_a_sale() -> Result<()> {
+    use chrono::{TimeZone, Utc};
+
+    temp_store!(store, home, false);
+    two_lots_portfolio(home.path(), "Fifo")?;
+    append_lines(
+        &home.path().join("trades.tsv"),
+        &[
+            "IB	2017/02/01	TrOut	Vanguard FTSE	8	0	0	1	1",
+            "Fid	2017/02/01	TrIn	Vanguard FTSE	8	0	0	1	1",
+        ],
+    )?;
+
+    // FIFO moves the 5 units left of the first lot and 3 of the second, basis and all.
+    let book = store.lots()?;
+    assert_eq!(1, book.sales.len());
+    let fid = book.open_lots("Fid", "Vanguard FTSE");
+    let moved: Vec<_> = fid.iter().map(|l| (l.acquired, l.units, l.basis)).collect();
+    assert_eq!(
+        vec![
+            (Utc.ymd(2016, 1, 4).and_hms(0, 0, 0), 5.0, 500.0),
+            (Utc.ymd(2016, 6, 4).and_hms(0, 0, 0), 3.0, 150.0)
+        ],
+        moved
+    );
+    let ib = book.open_lots("IB", "Vanguard FTSE");
+    assert_eq!((7.0, 350.0), (ib[0].units, ib[0].basis));
+    Ok(())
+}
+
+#[test]
+fn transfers_out_no_account_receives_are_sales() -> Result<()> {
+    use chrono::{TimeZone, Utc};
+
+    temp_store!(store, home, false);
+    two_lots_portfolio(home.path(), "Fifo")?;
+    append_lines(
+        &home.path().join("trades.tsv"),
+        &[
+            "IB	2017/02/01	TrOut	Vanguard FTSE	8	110	0	1	1",
+            "Fid	2017/03/01	TrIn	Vanguard FTSE	8	120	0	1	1",
+        ],
+    )?;
+
+    // The lots leave at the price of the transfer, the later one doesn't bring them back.
+    let book = store.lots()?;
+    assert_eq!(2, book.sales.len());
+    let out = &book.sales[1];
+    assert_eq!(Utc.ymd(2017, 2, 1).and_hms(0, 0, 0), out.date);
+    assert_eq!((880.0, 650.0), (out.proceeds, out.basis()));
+    let fid = book.open_lots("Fid", "Vanguard FTSE");
+    let lots: Vec<_> = fid.iter().map(|l| (l.acquired, l.units, l.basis)).collect();
+    assert_eq!(
+        vec![(Utc.ymd(2017, 3, 1).and_hms(0, 0, 0), 8.0, 960.0)],
+        lots
+    );
+    // One line for the lot the sale closed, two for the lots transferred out.
+    assert_eq!(3, store.gains(2017)?.len());
+    Ok(())
+}
+
+#[test]
+fn splits_adjust_lot_units_not_basis() -> Result<()> {
+    temp_store!(store, home, false);
+    two_lots_portfolio(home.path(), "Fifo")?;
+    append_lines(
+        &home.path().join("trades.tsv"),
+        &["IB	2017/02/01	Split	Vanguard FTSE	0	0	0	2	1"],
+    )?;
+
+    let book = store.lots()?;
+    let lots = book.open_lots("IB", "Vanguard FTSE");
+    assert_eq!(2, lots.len());
+    assert_eq!(10.0, lots[0].units);
+    assert_eq!(50.0, lots[0].price());
+    assert_eq!(20.0, lots[1].units);
+    assert_eq!(25.0, lots[1].price());
+    Ok(())
+}
+
+#[test]
+fn tax_status_is_computed_per_lot_and_jurisdiction() -> Result<()> {
+    use chrono::{TimeZone, Utc};
+
+    temp_store!(store, home, false);
+    two_lots_portfolio(home.path(), "Fifo	Longer")?;
+    append_lines(&home.path().join("jurisdictions.tsv"), &["Longer	730"])?;
+
+    // FIFO leaves 5 units bought on 2016/01/04 and 10 bought on 2016/06/04.
+    let book = store.lots()?;
+    assert_eq!(730, book.long_term_days("IB"));
+
+    let h = book.holding("IB", "Vanguard FTSE", Utc.ymd(2018, 3, 1).and_hms(0, 0, 0));
+    assert_eq!(5.0, h.lt_units);
+    assert_eq!(10.0, h.st_units);
+    assert_eq!(Some(Utc.ymd(2018, 6, 4).and_hms(0, 0, 0)), h.next_lt);
+
+    let h = book.holding("IB", "Vanguard FTSE", Utc.ymd(2019, 1, 1).and_hms(0, 0, 0));
+    assert_eq!(15.0, h.lt_units);
+    assert_eq!(None, h.next_lt);
+    Ok(())
+}
+
+#[test]
+fn gains_lists_closed_lots_for_the_year() -> Result<()> {
+    temp_store!(store, home, false);
+    two_lots_portfolio(home.path(), "Fifo")?;
+    append_lines(
+        &home.path().join("trades.tsv"),
+        &["IB	2017/05/01	Sell	Vanguard FTSE	10	40	4	1	1"],
+    )?;
+
+    assert_eq!(0, store.gains(2016)?.len());
+
+    // FIFO closes the rest of the 2016/01/04 lot and half of the 2016/06/04 one.
+    let v = store.gains(2017)?;
+    assert_eq!(3, v.len());
+    assert_eq!("LT", v[1].term);
+    assert_eq!(5.0, v[1].units);
+    assert_eq!(200.0, v[1].proceeds);
+    assert_eq!(2.0, v[1].fees);
+    assert_eq!(200.0 - 2.0 - 500.0, v[1].gain);
+    assert_eq!("ST", v[2].term);
+    assert_eq!(250.0, v[2].basis);
+
+    let sale = &store.lots()?.sales[1];
+    assert_eq!(sale.proceeds, v[1].proceeds + v[2].proceeds);
+    assert_eq!(sale.gain(), v[1].gain + v[2].gain);
+    Ok(())
+}
+
+#[test]
+fn wash_sales_move_the_loss_to_the_replacement_lot() -> Result<()> {
+    temp_store!(store, home, false);
+    append_lines(
+        &home.path().join("stocks.tsv"),
+        &[
+            "CashIB	Cash	Cash	Cash	A		USD	USD",
+            "Vanguard FTSE	Stock	Equity	World	C	VWRL	USD	USD	World",
+            "iShares World	Stock	Equity	World	C	IWRD	USD	USD	World",
+        ],
+    )?;
+    append_lines(
+        &home.path().join("trades.tsv"),
+        &[
+            "IB	2015/04/27	TrIn	CashIB	10000	1	0	1	1",
+            "IB	2016/01/04	Buy	Vanguard FTSE	10	100	0	1	1",
+            "IB	2016/02/20	Buy	iShares World	4	90	0	1	1",
+            "IB	2016/03/01	Sell	Vanguard FTSE	10	80	0	1	1",
+            "IB	2016/03/15	Buy	Vanguard FTSE	10	85	0	1	1",
+        ],
+    )?;
+
+    // 4 units are replaced by the purchase before the sale, 6 by the one after it.
+    let sales = store.wash_sales()?;
+    assert_eq!(1, sales.len());
+    assert_eq!(200.0, sales[0].wash);
+    assert_eq!(0.0, sales[0].gain());
+
+    let book = store.lots()?;
+    assert_eq!(360.0 + 80.0, book.open_lots("IB", "iShares World")[0].basis);
+    let vwrl = &book.open_lots("IB", "Vanguard FTSE")[0];
+    assert_eq!(850.0 + 120.0, vwrl.basis);
+    assert_eq!(120.0, vwrl.wash);
+
+    let gains = store.gains(2016)?;
+    assert_eq!(200.0, gains[0].wash);
+    assert_eq!(0.0, gains[0].gain);
+    Ok(())
+}
+
+#[test]
+fn wash_sale_replacements_are_taken_oldest_first_across_identical_stocks() -> Result<()> {
+    temp_store!(store, home, false);
+    append_lines(
+        &home.path().join("stocks.tsv"),
+        &[
+            "CashIB	Cash	Cash	Cash	A		USD	USD",
+            "Vanguard FTSE	Stock	Equity	World	C	VWRL	USD	USD	World",
+            "iShares World	Stock	Equity	World	C	IWRD	USD	USD	World",
+        ],
+    )?;
+    append_lines(
+        &home.path().join("trades.tsv"),
+        &[
+            "IB	2015/04/27	TrIn	CashIB	10000	1	0	1	1",
+            "IB	2016/01/04	Buy	Vanguard FTSE	10	100	0	1	1",
+            "IB	2016/02/10	Buy	Vanguard FTSE	10	90	0	1	1",
+            "IB	2016/02/20	Buy	iShares World	4	90	0	1	1",
+            "IB	2016/03/01	Sell	Vanguard FTSE	10	80	0	1	1",
+        ],
+    )?;
+
+    // The lot bought on 2016/02/10 replaces all the units sold, none are left for iShares.
+    let book = store.lots()?;
+    assert_eq!(200.0, book.sales[0].wash);
+    assert_eq!(200.0, book.open_lots("IB", "Vanguard FTSE")[0].wash);
+    assert_eq!(0.0, book.open_lots("IB", "iShares World")[0].wash);
+    Ok(())
+}
+
+#[tokio::test]
+async fn update_prices_from_quote_files() -> Result<()> {
+    temp_store!(store, home, false);
+    append_lines(
+        &home.path().join("stocks.tsv"),
+        &[
+            "Vanguard FTSE	Stock	Equity	World	C	VWRL	USD	USD		File",
+            "iShares World	Stock	Equity	World	C	IWRD	EUR	EUR		File",
+        ],
+    )?;
+    let quotes = home.path().join("quotes");
+    std::fs::create_dir(&quotes).chain_err(|| "Can't create quotes dir")?;
+    append_lines(
+        &quotes.join("funds.csv"),
+        &[
+            "ticker,price,date",
+            "VWRL,101.5,2021/03/01",
+            "VWRL,102.5,2021/03/02",
+        ],
+    )?;
+    append_lines(
+        &quotes.join("more.tsv"),
+        &[
+            "ticker	price	date",
+            "IWRD	55	2021/03/02",
+            "EURUSD=X	1.2	2021/03/02",
+        ],
+    )?;
+
+    store
+        .update_prices(lupo::providers::ProviderKind::File, None)
+        .await?;
+
+    let prices = store.load_prices()?;
+    assert_eq!(102.5, prices["VWRL"].price);
+    assert_eq!(55.0, prices["IWRD"].price);
+    assert_eq!(1.2, prices["EURUSD=X"].price);
+    assert!(!prices.contains_key("GBPUSD=X"));
+    Ok(())
+}
+
+#[tokio::test]
+async fn prices_are_kept_as_history() -> Result<()> {
+    use chrono::{TimeZone, Utc};
+    use lupo::providers::ProviderKind;
+
+    temp_store!(store, home, false);
+    append_lines(
+        &home.path().join("stocks.tsv"),
+        &["Vanguard FTSE	Stock	Equity	World	C	VWRL	USD	USD		File"],
+    )?;
+    let quotes = home.path().join("quotes");
+    std::fs::create_dir(&quotes).chain_err(|| "Can't create quotes dir")?;
+    append_lines(
+        &quotes.join("a.tsv"),
+        &["ticker	price	date", "VWRL	100	2021/03/01"],
+    )?;
+    store.update_prices(ProviderKind::File, None).await?;
+    store.update_prices(ProviderKind::File, None).await?;
+
+    append_lines(
+        &quotes.join("b.tsv"),
+        &["ticker	price	date", "VWRL	110	2021/03/05"],
+    )?;
+    store.update_prices(ProviderKind::File, None).await?;
+
+    // Re-runs don't duplicate prices already in the file.
+    let content = std::fs::read_to_string(home.path().join("prices.tsv"))
+        .chain_err(|| "Can't read prices")?;
+    assert_eq!(2, content.matches("VWRL").count());
+
+    assert_eq!(110.0, store.load_prices()?["VWRL"].price);
+    let at = |d| store.price_at("VWRL", Utc.ymd(2021, 3, d).and_hms(0, 0, 0));
+    assert_eq!(100.0, at(4)?.unwrap().price);
+    assert_eq!(110.0, at(5)?.unwrap().price);
+    assert!(store
+        .price_at("VWRL", Utc.ymd(2021, 2, 1).and_hms(0, 0, 0))?
+        .is_none());
+    Ok(())
+}
+
+#[tokio::test]
+async fn backfill_downloads_prices_since_the_first_trade() -> Result<()> {
+    use chrono::{TimeZone, Utc};
+    use lupo::providers::ProviderKind;
+
+    temp_store!(store, home, false);
+    append_lines(
+        &home.path().join("stocks.tsv"),
+        &[
+            "CashIB	Cash	Cash	Cash	A		USD	USD",
+            "CashEU	Cash	Cash	Cash	A		EUR	EUR",
+            "Vanguard FTSE	Stock	Equity	World	C	VWRL	USD	USD",
+        ],
+    )?;
+    append_lines(
+        &home.path().join("trades.tsv"),
+        &[
+            "IB	2021/02/20	TrIn	CashIB	10000	1	0	1	1",
+            "IB	2021/03/01	Buy	Vanguard FTSE	10	100	0	1	1",
+        ],
+    )?;
+    let quotes = home.path().join("quotes");
+    std::fs::create_dir(&quotes).chain_err(|| "Can't create quotes dir")?;
+    append_lines(
+        &quotes.join("a.tsv"),
+        &[
+            "ticker	price	date",
+            "VWRL	98	2021/02/25",
+            "VWRL	100	2021/03/01",
+            "VWRL	101	2021/03/02",
+            "EURUSD=X	1.2	2021/02/20",
+        ],
+    )?;
+    store.backfill_prices(ProviderKind::File, None).await?;
+
+    append_lines(
+        &quotes.join("b.tsv"),
+        &["ticker	price	date", "VWRL	105	2021/03/10"],
+    )?;
+    store.backfill_prices(ProviderKind::File, None).await?;
+
+    let history = store.load_price_history()?;
+    let day = |m, d| Utc.ymd(2021, m, d).and_hms(0, 0, 0);
+    assert!(history.at("VWRL", day(2, 28)).is_none());
+    assert_eq!(101.0, history.at("VWRL", day(3, 5)).unwrap().price);
+    assert_eq!(105.0, history.at("VWRL", day(3, 10)).unwrap().price);
+    assert_eq!(1.2, history.at("EURUSD=X", day(2, 20)).unwrap().price);
+    assert_eq!(1.0, history.at("USDUSD=X", day(2, 20)).unwrap().price);
+    Ok(())
+}
+
+#[test]
+fn port_as_of_a_past_date() -> Result<()> {
+    use chrono::{TimeZone, Utc};
+
+    temp_store!(store, home, false);
+    two_accounts_portfolio(home.path())?;
+    append_lines(
+        &home.path().join("prices.tsv"),
+        &["VWRL	90	2016/01/10", "USDUSD=X	1	2015/01/01"],
+    )?;
+
+    let as_of = Some(Utc.ymd(2016, 1, 31).and_hms(0, 0, 0));
+    let port = store.port(false, true, false, as_of)?;
+    let vwrl: Vec<_> = port.iter().filter(|l| l.name == "Vanguard FTSE").collect();
+    assert_eq!(1, vwrl.len());
+    assert_eq!("IB", vwrl[0].account);
+    assert_eq!(90.0, vwrl[0].price);
+    assert_eq!(900.0, vwrl[0].amount);
+
+    assert_eq!(20000.0 - 1000.0 + 900.0, store.total(as_of)?);
+    Ok(())
+}
+
+#[test]
+fn performance_per_period_and_segment() -> Result<()> {
+    use chrono::{TimeZone, Utc};
+
+    temp_store!(store, home, false);
+    append_lines(
+        &home.path().join("stocks.tsv"),
+        &[
+            "CashIB	Cash	Cash	Cash	A		USD	USD",
+            "Vanguard FTSE	Stock	Equity	World	C	VWRL	USD	USD",
+        ],
+    )?;
+    append_lines(
+        &home.path().join("trades.tsv"),
+        &[
+            "IB	2020/01/01	TrIn	CashIB	1000	1	0	1	1",
+            "IB	2020/01/01	Buy	Vanguard FTSE	10	100	0	1	1",
+            "IB	2020/07/01	TrIn	CashIB	1000	1	0	1	1",
+        ],
+    )?;
+    append_lines(
+        &home.path().join("prices.tsv"),
+        &[
+            "ticker	price	date",
+            "USDUSD=X	1	2019/01/01",
+            "VWRL	100	2019/12/01",
+            "VWRL	110	2020/06/30",
+            "VWRL	121	2020/12/31",
+        ],
+    )?;
+
+    let perf = store.performance(Some(Utc.ymd(2020, 12, 31).and_hms(0, 0, 0)))?;
+    let find = |segment: &str, period: &str| {
+        perf.iter()
+            .find(|p| p.segment == segment && p.period == period)
+            .unwrap()
+    };
+    let close = |a: f64, b: f64| (a - b).abs() < 1e-6;
+
+    // Too young for a 3 years return.
+    assert!(perf.iter().all(|p| p.period != "3Y"));
+
+    // The group only gets the money used to buy it, so both returns are the price change.
+    let equity = find("Group Equity", "ITD");
+    assert!(close(0.21, equity.twr.unwrap()));
+    assert!(close(0.21, equity.irr.unwrap()));
+    assert!(close(0.1, find("Group Equity", "MTD").twr.unwrap()));
+
+    // The second deposit sits in cash, so the portfolio grows less than the stock after it.
+    let port = find("Portfolio", "ITD");
+    assert!(close(1.1 * (1.0 + 110.0 / 2100.0) - 1.0, port.twr.unwrap()));
+    assert!(port.irr.unwrap() > 0.0 && port.irr.unwrap() < 0.21);
+    assert_eq!(port.twr, find("Account IB", "ITD").twr);
+    Ok(())
+}
+
+#[test]
+fn port_is_valued_in_the_base_currency() -> Result<()> {
+    temp_store!(_store, home, false);
+    two_accounts_portfolio(home.path())?;
+    std::fs::write(home.path().join("config.tsv"), "Name	Value\nCurrency	EUR\n")
+        .chain_err(|| "Can't write config file")?;
+    append_lines(
+        &home.path().join("prices.tsv"),
+        &["USDEUR=X	0.9	2015/01/01"],
+    )?;
+
+    let store = lupo::Store::open(home.path())?;
+    assert_eq!("EUR", store.currency);
+
+    let port = store.port(false, false, true, None)?;
+    let vwrl = port.iter().find(|l| l.name == "Vanguard FTSE").unwrap();
+    assert!((3300.0 * 0.9 - vwrl.amount).abs() < 1e-9);
+
+    let cash = port.iter().find(|l| l.name == "_Cash").unwrap();
+    assert_eq!("EUR", cash.currency);
+    assert!(((20000.0 - 1000.0 - 1800.0) * 0.9 - cash.amount).abs() < 1e-9);
+    Ok(())
+}
+
+#[test]
+fn filled_rates_are_into_dollars_and_carried_on_into_the_base_currency() -> Result<()> {
+    temp_store!(_store, home, false);
+    std::fs::write(home.path().join("config.tsv"), "Name	Value\nCurrency	EUR\n")
+        .chain_err(|| "Can't write config file")?;
+    append_lines(
+        &home.path().join("stocks.tsv"),
+        &[
+            "CashIB	Cash	Cash	Cash	A		USD	USD",
+            "Vanguard Total	Stock	Equity	World	C	VT	USD	USD",
+        ],
+    )?;
+    append_lines(
+        &home.path().join("trades.tsv"),
+        &[
+            "IB	2020/01/02	TrIn	CashIB	10000	1	0	1	1",
+            "IB	2020/01/02	Buy	Vanguard Total	10	90	0	1	1",
+            "IB	2020/06/01	Sell	Vanguard Total	5	100	0	1	1",
+        ],
+    )?;
+    append_lines(
+        &home.path().join("prices.tsv"),
+        &[
+            "ticker	price	date",
+            "VT	100	2020/06/01",
+            "USDEUR=X	0.5	2020/01/01",
+            "USDEUR=X	0.6	2020/06/01",
+        ],
+    )?;
+    let store = lupo::Store::open(home.path())?;
+    let close = |a: f64, b: f64| (a - b).abs() < 1e-9;
+
+    let ledger = store.ledger()?;
+    let rates: Vec<_> = ledger.iter().map(|t| t.rate()).collect();
+    assert_eq!(vec![0.5, 0.5, 0.6], rates);
+
+    let port = store.port(false, true, false, None)?;
+    let vt = port.iter().find(|l| l.name == "Vanguard Total").unwrap();
+    assert!(close(5.0 * 100.0 * 0.6, vt.amount));
+    assert!(close(5.0 * 90.0 * 0.5, vt.basis));
+    assert!(close(75.0 + 75.0, vt.gain));
+    assert!(close(45.0 + 45.0, vt.fx_gain));
+
+    // The cash stays in dollars and is valued at the rate of the day.
+    let cash = port.iter().find(|l| l.name == "CashIB").unwrap();
+    assert!(close(10000.0 - 900.0 + 500.0, cash.units));
+    assert!(close(9600.0 * 0.6, cash.amount));
+
+    let gains = store.gains(2020)?;
+    assert_eq!(1, gains.len());
+    assert!(close(300.0, gains[0].proceeds));
+    assert!(close(225.0, gains[0].basis));
+    assert!(close(75.0, gains[0].gain));
+    Ok(())
+}
+
+#[tokio::test]
+async fn the_dollar_is_quoted_in_the_base_currency_for_trade_rates() -> Result<()> {
+    use lupo::providers::ProviderKind;
+
+    temp_store!(_store, home, false);
+    std::fs::write(home.path().join("config.tsv"), "Name	Value\nCurrency	EUR\n")
+        .chain_err(|| "Can't write config file")?;
+    append_lines(
+        &home.path().join("stocks.tsv"),
+        &["Nestle	Stock	Equity	Swiss	C	NESN	CHF	CHF		File"],
+    )?;
+    let quotes = home.path().join("quotes");
+    std::fs::create_dir(&quotes).chain_err(|| "Can't create quotes dir")?;
+    append_lines(
+        &quotes.join("a.tsv"),
+        &[
+            "ticker	price	date",
+            "NESN	100	2021/03/02",
+            "CHFEUR=X	0.9	2021/03/02",
+            "USDEUR=X	0.8	2021/03/02",
+        ],
+    )?;
+
+    let store = lupo::Store::open(home.path())?;
+    store.update_prices(ProviderKind::File, None).await?;
+    let prices = store.load_prices()?;
+    assert_eq!(0.9, prices["CHFEUR=X"].price);
+    assert_eq!(0.8, prices["USDEUR=X"].price);
+    Ok(())
+}
+
+#[tokio::test]
+async fn missing_exchange_rates_are_crossed_through_the_dollar() -> Result<()> {
+    use lupo::providers::ProviderKind;
+
+    temp_store!(_store, home, false);
+    std::fs::write(home.path().join("config.tsv"), "Name	Value\nCurrency	EUR\n")
+        .chain_err(|| "Can't write config file")?;
+    append_lines(
+        &home.path().join("stocks.tsv"),
+        &[
+            "CashIB	Cash	Cash	Cash	A		EUR	EUR",
+            "Nestle	Stock	Equity	Swiss	C	NESN	CHF	CHF		File",
+        ],
+    )?;
+    append_lines(
+        &home.path().join("trades.tsv"),
+        &["IB	2021/03/02	TrIn	Nestle	10	100	0	1	1"],
+    )?;
+    let quotes = home.path().join("quotes");
+    std::fs::create_dir(&quotes).chain_err(|| "Can't create quotes dir")?;
+    append_lines(
+        &quotes.join("a.tsv"),
+        &[
+            "ticker	price	date",
+            "NESN	100	2021/03/02",
+            "CHFUSD=X	1.1	2021/03/02",
+            "USDEUR=X	0.8	2021/03/02",
+            "GBPUSD=X	1.4	2021/03/02",
+        ],
+    )?;
+
+    let store = lupo::Store::open(home.path())?;
+    let updates = store.update_prices(ProviderKind::File, None).await?;
+    let update = |t: &str| updates.iter().find(|u| u.ticker == t).unwrap();
+    assert!(update("CHFEUR=X").prices.is_err());
+    assert_eq!(1.1, update("CHFUSD=X").prices.as_ref().unwrap()[0].price);
+
+    let prices = store.load_prices()?;
+    assert!(!prices.contains_key("CHFEUR=X"));
+    assert!(!prices.contains_key("GBPUSD=X"));
+    assert_eq!(1.1, prices["CHFUSD=X"].price);
+
+    let port = store.port(false, true, false, None)?;
+    let nestle = port.iter().find(|l| l.name == "Nestle").unwrap();
+    assert!((1000.0 * 1.1 * 0.8 - nestle.amount).abs() < 1e-9);
+    assert!(!nestle.error.contains("CN"));
+    Ok(())
+}
+
+#[test]
+fn gain_is_split_in_price_and_exchange_rate() -> Result<()> {
+    use chrono::{TimeZone, Utc};
+
+    temp_store!(store, home, false);
+    append_lines(
+        &home.path().join("stocks.tsv"),
+        &[
+            "CashIB	Cash	Cash	Cash	A		USD	USD",
+            "Siemens	Stock	Equity	Germany	C	SIE	EUR	EUR",
+        ],
+    )?;
+    // The exchange rate of trades with no 'Currency' comes from the prices.
+    append_lines(
+        &home.path().join("trades.tsv"),
+        &[
+            "IB	2020/01/01	TrIn	CashIB	10000	1	0	1	1",
+            "IB	2020/01/02	Buy	Siemens	10	100	0	1",
+            "IB	2020/06/01	Sell	Siemens	5	110	0	1	",
+        ],
+    )?;
+    append_lines(
+        &home.path().join("prices.tsv"),
+        &[
+            "ticker	price	date",
+            "USDUSD=X	1	2020/01/01",
+            "EURUSD=X	1.1	2020/01/01",
+            "EURUSD=X	1.2	2020/05/29",
+            "SIE	110	2020/06/01",
+        ],
+    )?;
+
+    let as_of = Some(Utc.ymd(2020, 6, 1).and_hms(0, 0, 0));
+    let port = store.port(false, true, false, as_of)?;
+    let sie = port.iter().find(|l| l.name == "Siemens").unwrap();
+    let close = |a: f64, b: f64| (a - b).abs() < 1e-9;
+
+    assert!(close(5.0 * 110.0 * 1.2, sie.amount));
+    assert!(close(5.0 * 100.0 * 1.1, sie.basis));
+    // Realized and unrealized: 10 units bought for 1100 are worth 1320.
+    assert!(close(220.0, sie.gain));
+    assert!(close(10.0 * 100.0 * (1.2 - 1.1), sie.fx_gain));
+    assert!(close(10.0 * (110.0 - 100.0) * 1.2, sie.price_gain));
+
+    let gains = store.gains(2020)?;
+    assert!(close(5.0 * 110.0 * 1.2 - 5.0 * 100.0 * 1.1, gains[0].gain));
+    Ok(())
+}
+
+#[test]
+fn output_formats_are_parsed_ignoring_case() {
+    use lupo::output::Format;
+
+    assert_eq!(Format::Json, "JSON".parse::<Format>().unwrap());
+    assert_eq!(Format::Tsv, "tsv".parse::<Format>().unwrap());
+    assert!("xml".parse::<Format>().is_err());
+}
+
+#[test]
+fn exchange_rate_tickers_are_split_in_their_currencies() {
+    use lupo::prices::fx_pair;
+
+    let pair = |a: &str, b: &str| Some((a.to_string(), b.to_string()));
+    assert_eq!(pair("EUR", "USD"), fx_pair("EURUSD=X"));
+    assert_eq!(None, fx_pair("VWRL"));
+    assert_eq!(None, fx_pair("ABÄCD=X"));
+}
+
+#[test]
+fn trades_and_stocks_are_returned_filtered_by_name() -> Result<()> {
+    temp_store!(store, home, false);
+    two_accounts_portfolio(home.path())?;
+
+    let trades = store.trades(Some("vanguard".to_string()))?;
+    assert_eq!(2, trades.len());
+    assert_eq!("IB", trades[0].account);
+    assert_eq!("Fid", trades[1].account);
+
+    let stocks = store.stocks(Some("cash".to_string()))?;
+    let names: Vec<_> = stocks.iter().map(|s| &s.name[..]).collect();
+    assert_eq!(vec!["CashFid", "CashIB"], names);
+    Ok(())
+}
+
+#[test]
+fn ledger_queries_trades_by_account_stock_date_and_type() -> Result<()> {
+    use chrono::{TimeZone, Utc};
+    use lupo::ledger::TradeQuery;
+    use lupo::TradeType;
+
+    temp_store!(store, home, false);
+    two_accounts_portfolio(home.path())?;
+    let ledger = store.ledger()?;
+
+    assert_eq!(4, ledger.len());
+    assert_eq!(2, ledger.by_account("IB").count());
+    assert_eq!(2, ledger.by_stock("Vanguard FTSE").count());
+    assert_eq!(2, ledger.by_type(TradeType::TrIn).count());
+    let day = |y, m, d| Utc.ymd(y, m, d).and_hms(0, 0, 0);
+    assert_eq!(
+        2,
+        ledger.between(day(2016, 1, 1), day(2016, 12, 31)).count()
+    );
+
+    let q = TradeQuery {
+        account: Some("Fid".to_string()),
+        r#type: Some(TradeType::Buy),
+        ..Default::default()
+    };
+    let fid_buys: Vec<_> = ledger.query(&q).collect();
+    assert_eq!(1, fid_buys.len());
+    assert_eq!(20.0, fid_buys[0].units);
+
+    let lines: Vec<u64> = ledger.iter_with_lines().map(|(l, _)| l).collect();
+    assert_eq!(vec![2, 3, 4, 5], lines);
+    Ok(())
+}
+
+#[test]
+fn added_trades_are_validated_and_get_the_exchange_rate_of_their_date() -> Result<()> {
+    use chrono::{TimeZone, Utc};
+    use lupo::{Trade, TradeType};
+
+    temp_store!(_store, home, false);
+    two_accounts_portfolio(home.path())?;
+    std::fs::write(home.path().join("config.tsv"), "Name	Value\nCurrency	EUR\n")
+        .chain_err(|| "Can't write config file")?;
+    append_lines(
+        &home.path().join("prices.tsv"),
+        &["USDEUR=X	0.9	2015/01/01"],
+    )?;
+    let store = lupo::Store::open(home.path())?;
+
+    let buy = |stock: &str, price| Trade {
+        account: "IB".to_string(),
+        date: Utc.ymd(2016, 3, 2).and_hms(0, 0, 0),
+        r#type: TradeType::Buy,
+        stock: stock.to_string(),
+        units: 10.0,
+        price,
+        fees: Some(1.0),
+        split: 1.0,
+        currency: None,
+        lot: None,
+    };
+    assert!(store.add_trade(buy("Vanguard", Some(95.2))).is_err());
+    assert!(store.add_trade(buy("Vanguard FTSE", None)).is_err());
+    assert_eq!(4, store.ledger()?.len());
+
+    // The trades file keeps the rate into dollars, the ledger the one into the base currency.
+    let added = store.add_trade(buy("Vanguard FTSE", Some(95.2)))?;
+    assert_eq!(Some(1.0), added.currency);
+
+    let ledger = store.ledger()?;
+    assert_eq!(5, ledger.len());
+    let (line, last) = ledger.iter_with_lines().last().unwrap();
+    assert_eq!(6, line);
+    assert_eq!("Vanguard FTSE", last.stock);
+    assert_eq!(Some(95.2), last.price);
+    assert_eq!(Some(0.9), last.currency);
+    Ok(())
+}
+
+#[test]
+fn backdated_trades_are_added_in_date_order() -> Result<()> {
+    use chrono::{TimeZone, Utc};
+    use lupo::storage::StorageKind;
+
+    temp_store!(store, home, false);
+    two_accounts_portfolio(home.path())?;
+    let (_, mut trade) = store.read_trades()?.pop().unwrap();
+    trade.date = Utc.ymd(2016, 1, 4).and_hms(0, 0, 0);
+    trade.units = 5.0;
+    store.add_trade(trade.clone())?;
+
+    // It goes after the trades of its day, before the later ones.
+    let dates: Vec<_> = store
+        .read_trades()?
+        .into_iter()
+        .map(|(l, t)| (l, t.date))
+        .collect();
+    assert_eq!(5, dates.len());
+    assert_eq!((5, trade.date), dates[3]);
+    assert!(dates.windows(2).all(|w| w[0].1 <= w[1].1));
+    assert!(store.check_errors()?.is_empty());
+
+    // Trades older than all the others go first, whatever the storage.
+    store.migrate(StorageKind::Sqlite)?;
+    let store = lupo::Store::open(home.path())?;
+    trade.date = Utc.ymd(2015, 1, 1).and_hms(0, 0, 0);
+    trade.r#type = lupo::TradeType::TrIn;
+    store.add_trade(trade.clone())?;
+    let (line, first) = store
+        .ledger()?
+        .iter_with_lines()
+        .next()
+        .map(|(l, t)| (l, t.clone()))
+        .unwrap();
+    assert_eq!(1, line);
+    assert_eq!(trade.date, first.date);
+    assert!(store.check_errors()?.is_empty());
+    Ok(())
+}
+
+#[test]
+fn trade_drafts_show_the_position_change_before_being_added() -> Result<()> {
+    use lupo::{TradeDraft, TradeType};
+
+    temp_store!(store, home, false);
+    two_accounts_portfolio(home.path())?;
+
+    let mut draft = TradeDraft {
+        r#type: Some(TradeType::Sell),
+        stock: Some("Vanguard FTSE".to_string()),
+        units: Some(4.0),
+        ..Default::default()
+    };
+    assert!(draft.clone().into_trade().is_err());
+    draft.account = Some("IB".to_string());
+    draft.price = store.last_price("Vanguard FTSE")?.map(|p| p.price);
+    assert_eq!(Some(110.0), draft.price);
+    assert_eq!(vec!["Fid", "IB"], store.accounts()?);
+
+    let trade = store.complete_trade(draft.into_trade()?)?;
+    let changes = store.position_change(&trade)?;
+    assert_eq!(2, changes.len());
+    assert_eq!(
+        ("Vanguard FTSE", 10.0, 6.0),
+        (&changes[0].stock[..], changes[0].before, changes[0].after)
+    );
+    assert_eq!(
+        ("CashIB", 9000.0, 9440.0),
+        (&changes[1].stock[..], changes[1].before, changes[1].after)
+    );
+    Ok(())
+}
+
+#[test]
+fn check_reports_semantic_errors_with_file_and_line() -> Result<()> {
+    temp_store!(store, home, false);
+    two_accounts_portfolio(home.path())?;
+    assert!(store.check_errors()?.is_empty());
+
+    append_lines(
+        &home.path().join("stocks.tsv"),
+        &[
+            "Vanguard FTSE	Stock	Equity	World	C	VWRL	USD	USD",
+            "Apple	Stock	Equity	US	C	AAPL	USD	USD",
+        ],
+    )?;
+    append_lines(
+        &home.path().join("trades.tsv"),
+        &[
+            "IB	2016/03/01	Sell	Vanguard FTSE	15	105	1	1	1",
+            "IB	2016/02/01	Buy	Apple	1		0	1	1",
+            "IB	2016/03/02	Split	Apple	0	0	0	0	1",
+            "Schwab	2016/03/03	Buy	Tesla	1	10	0	1	1",
+        ],
+    )?;
+
+    let errors: Vec<String> = store
+        .check_errors()?
+        .iter()
+        .map(|e| e.to_string())
+        .collect();
+    assert_eq!(
+        vec![
+            "stocks.tsv:5: Stock Vanguard FTSE is already defined at line 4",
+            "stocks.tsv:6: No prices for ticker AAPL of Apple",
+            "trades.tsv:6: -5 units of Vanguard FTSE in account IB",
+            "trades.tsv:7: Trade on 2016/02/01 is before the one on 2016/03/01 at line 6",
+            "trades.tsv:7: Buy without a price",
+            "trades.tsv:8: Split with a ratio of 0",
+            "trades.tsv:9: Unknown stock Tesla",
+            "trades.tsv:9: No CashSchwab stock for the cash of account Schwab",
+        ],
+        errors
+    );
+    Ok(())
+}
+
+#[test]
+fn bad_trades_stop_port_with_their_line_or_are_skipped_when_lenient() -> Result<()> {
+    temp_store!(_store, home, false);
+    two_accounts_portfolio(home.path())?;
+    let trades = home.path().join("trades.tsv");
+    append_lines(
+        &trades,
+        &[
+            "IB	2016/03/01	Buy	Vanguard FTS	1	100	0	1	1",
+            "Schwab	2016/03/01	Buy	Vanguard FTSE	1	100	0	1	1",
+        ],
+    )?;
+    let err = lupo::Store::open(home.path())?
+        .port(false, false, false, None)
+        .unwrap_err();
+    assert_eq!("trades.tsv:6: Unknown stock Vanguard FTS", err.to_string());
+
+    append_lines(&trades, &["IB	2016/03/01	Buy	Vanguard FTSE	1	NaN	0	1	1"])?;
+    let mut store = lupo::Store::open(home.path())?;
+    let err = store.trades(None).unwrap_err();
+    assert_eq!(
+        "trades.tsv:8 column Price: 'NaN' is not a number",
+        err.to_string()
+    );
+
+    // A sale of more units than are held is left out of the portfolio as well as the lots.
+    append_lines(&trades, &["IB	2016/03/02	Sell	Vanguard FTSE	100	110	0	1	1"])?;
+    store.lenient = true;
+    let port = store.port(false, true, false, None)?;
+    assert_eq!(4, port.len());
+    assert_eq!(4, store.ledger()?.len());
+    let ib = port
+        .iter()
+        .find(|l| l.account == "IB" && l.name == "Vanguard FTSE")
+        .unwrap();
+    assert_eq!(10.0, ib.units);
+    assert_eq!(1001.0, ib.basis);
+    assert!(store.lots()?.sales.is_empty());
+    Ok(())
+}
+
+#[test]
+fn malformed_rows_are_reported_with_line_column_and_value() -> Result<()> {
+    let first_error = |home: &std::path::Path, line: &str| -> Result<String> {
+        std::fs::write(
+            home.join("trades.tsv"),
+            format!("{}\n{}\n", lupo::TRADE_COLUMNS.join("\t"), line),
+        )
+        .chain_err(|| "Can't write trades file")?;
+        let store = lupo::Store::open(home)?;
+        Ok(store.check().unwrap_err().to_string())
+    };
+
+    temp_store!(_store, home, false);
+    assert_eq!(
+        "trades.tsv:2 column Type: 'XTrIn' is not one of Buy, Sell, TrIn, Div, TrOut, Split",
+        first_error(home.path(), "IB	2015/04/27	XTrIn	CashIB	1	1	0	1	1")?
+    );
+    assert_eq!(
+        "trades.tsv:2 column Date: '2015-04-27' is not a YYYY/MM/DD date",
+        first_error(home.path(), "IB	2015-04-27	TrIn	CashIB	1	1	0	1	1")?
+    );
+    assert_eq!(
+        "trades.tsv:2 column Units: 'ten' is not a number",
+        first_error(home.path(), "IB	2015/04/27	TrIn	CashIB	ten	1	0	1	1")?
+    );
+    // A value found in more than one column doesn't tell which one is wrong.
+    assert_eq!(
+        "trades.tsv:2: 'x' is not a YYYY/MM/DD date",
+        first_error(home.path(), "IB	x	TrIn	x	1	1	0	1	1")?
+    );
+    Ok(())
+}
+
+#[test]
+fn the_portfolio_is_locked_and_files_are_replaced_whole() -> Result<()> {
+    use fs2::FileExt;
+
+    temp_store!(store, home, false);
+    two_accounts_portfolio(home.path())?;
+    store.append_prices(vec![lupo::PriceLine {
+        ticker: "VWRL".to_string(),
+        price: 120.0,
+        date: chrono::Utc::now(),
+    }])?;
+    let latest = store.load_prices()?;
+    assert_eq!(120.0, latest["VWRL"].price);
+    assert_eq!(1.0, latest["USDUSD=X"].price);
+    // Appended prices are written as the prices file is, with the text quoted.
+    let prices = std::fs::read_to_string(home.path().join("prices.tsv"))
+        .chain_err(|| "Can't read prices file")?;
+    assert_eq!(
+        Some(
+            &format!(
+                "\"VWRL\"\t120.0\t\"{}\"",
+                chrono::Utc::now().format("%Y/%m/%d")
+            )[..]
+        ),
+        prices.lines().last()
+    );
+    let names: Vec<_> = std::fs::read_dir(home.path())
+        .chain_err(|| "Can't list the directory")?
+        .filter_map(|e| e.ok())
+        .map(|e| e.file_name().to_string_lossy().to_string())
+        .filter(|n| n.ends_with(".tmp"))
+        .collect();
+    assert!(names.is_empty(), "{:?}", names);
+    drop(store);
+
+    // Another process holding the lock, as far as this one can tell.
+    let other = std::fs::File::open(home.path().join(lupo::files::LOCK_FILE))
+        .chain_err(|| "Can't open the lock file")?;
+    other
+        .try_lock_exclusive()
+        .chain_err(|| "Can't lock the directory")?;
+    let err = lupo::Store::open(home.path()).err().unwrap();
+    assert!(err.to_string().contains("in use"), "{}", err);
+
+    other.unlock().chain_err(|| "Can't unlock the directory")?;
+    lupo::Store::open(home.path())?;
+
+    // Readers share the lock with another reader, a writer has to wait for it.
+    FileExt::try_lock_shared(&other).chain_err(|| "Can't lock the directory")?;
+    let reader = lupo::Store::open_shared(home.path())?;
+    assert_eq!(4, reader.ledger()?.len());
+    let err = lupo::Store::open(home.path()).err().unwrap();
+    assert!(err.to_string().contains("in use"), "{}", err);
+    let (_, trade) = reader.read_trades()?.pop().unwrap();
+    let err = reader.add_trade(trade).unwrap_err();
+    assert!(err.to_string().contains("read only"), "{}", err);
+    drop(reader);
+    other.unlock().chain_err(|| "Can't unlock the directory")?;
+
+    // A portfolio created anew over an old one keeps the lock file it holds.
+    let store = lupo::Store::new(home.path(), true)?;
+    assert!(home.path().join(lupo::files::LOCK_FILE).exists());
+    assert!(!home.path().join("prices.tsv").exists());
+    assert_eq!(0, store.ledger()?.len());
+    Ok(())
+}
+
+#[cfg(target_family = "unix")]
+#[test]
+fn edits_are_checked_before_replacing_the_file() -> Result<()> {
+    use lupo::EditChoice;
+    use std::os::unix::fs::PermissionsExt;
+
+    temp_store!(store, home, false);
+    two_accounts_portfolio(home.path())?;
+    let trades = home.path().join("trades.tsv");
+    let before = std::fs::read_to_string(&trades).chain_err(|| "Can't read trades")?;
+
+    // An editor that appends the line in the LINE file to the file it edits.
+    let editor = home.path().join("editor.sh");
+    let line = home.path().join("LINE");
+    std::fs::write(
+        &editor,
+        format!("#!/bin/sh\ncat '{}' >> \"$1\"\n", line.to_string_lossy()),
+    )
+    .chain_err(|| "Can't write the editor")?;
+    std::fs::set_permissions(&editor, std::fs::Permissions::from_mode(0o755))
+        .chain_err(|| "Can't make the editor executable")?;
+    std::env::set_var("VISUAL", &editor);
+
+    let bad = "IB	2016/03/01	XBuy	Vanguard FTSE	1	100	0	1	1\n";
+    std::fs::write(&line, bad).chain_err(|| "Can't write the line")?;
+    let mut seen = Vec::new();
+    let saved = store.edit_trades(|errors| {
+        seen = errors.to_vec();
+        Ok(EditChoice::Discard)
+    })?;
+    assert!(!saved);
+    assert!(
+        seen[0].starts_with("trades.tsv:6 column Type: 'XBuy'"),
+        "{:?}",
+        seen
+    );
+    assert_eq!(
+        before,
+        std::fs::read_to_string(&trades).chain_err(|| "Can't read")?
+    );
+
+    // Edited again, the bad line is appended twice and saved anyway.
+    let mut asked = 0;
+    assert!(store.edit_trades(|_| {
+        asked += 1;
+        Ok(if asked == 1 {
+            EditChoice::Edit
+        } else {
+            EditChoice::Save
+        })
+    })?);
+    assert_eq!(2, asked);
+    let after = std::fs::read_to_string(&trades).chain_err(|| "Can't read")?;
+    assert_eq!(format!("{}{}{}", before, bad, bad), after);
+    assert!(!home.path().join(".trades.tsv.edit").exists());
+
+    std::fs::write(&trades, &before).chain_err(|| "Can't write trades")?;
+    let good = "IB	2016/03/01	Buy	Vanguard FTSE	1	100	0	1	1\n";
+    std::fs::write(&line, good).chain_err(|| "Can't write the line")?;
+    assert!(store.edit_trades(|_| Ok(EditChoice::Discard))?);
+    assert_eq!(5, store.ledger()?.len());
+
+    // Lenient mode doesn't skip the bad rows of the edited copy.
+    let mut store = store;
+    store.lenient = true;
+    std::fs::write(&line, bad).chain_err(|| "Can't write the line")?;
+    let mut seen = Vec::new();
+    assert!(!store.edit_trades(|errors| {
+        seen = errors.to_vec();
+        Ok(EditChoice::Discard)
+    })?);
+    assert!(
+        seen[0].starts_with("trades.tsv:7 column Type: 'XBuy'"),
+        "{:?}",
+        seen
+    );
+    assert!(!home.path().join(".trades.tsv.edit").exists());
+    Ok(())
+}
+
+#[test]
+fn changes_are_journaled_and_can_be_undone() -> Result<()> {
+    use chrono::{TimeZone, Utc};
+    use lupo::{Trade, TradeType};
+
+    temp_store!(store, home, false);
+    two_accounts_portfolio(home.path())?;
+    let buy = |units| Trade {
+        account: "IB".to_string(),
+        date: Utc.ymd(2016, 3, 2).and_hms(0, 0, 0),
+        r#type: TradeType::Buy,
+        stock: "Vanguard FTSE".to_string(),
+        units,
+        price: Some(100.0),
+        fees: None,
+        split: 1.0,
+        currency: Some(1.0),
+        lot: None,
+    };
+    store.add_trade(buy(1.0))?;
+    store.add_trade(buy(2.0))?;
+    assert_eq!(6, store.ledger()?.len());
+
+    let history = store.history()?;
+    assert_eq!(vec![1, 2], history.iter().map(|c| c.id).collect::<Vec<_>>());
+    assert_eq!(
+        ("trades.tsv", "add", 1, 0),
+        (
+            &history[1].file[..],
+            &history[1].command[..],
+            history[1].added,
+            history[1].removed
+        )
+    );
+    assert_eq!(
+        "@@ -6,0 +7 @@\n+IB	2016/03/02	Buy	Vanguard FTSE	2	100		1	1	\n",
+        store.change_diff(2)?
+    );
+
+    // Changes keep their diff, the journal a single copy of the file.
+    let copies = std::fs::read_dir(home.path().join("journal"))
+        .chain_err(|| "Can't read the journal")?
+        .filter(|e| {
+            e.as_ref()
+                .is_ok_and(|e| e.file_name().to_string_lossy().ends_with("trades.tsv"))
+        })
+        .count();
+    assert_eq!(1, copies);
+
+    let undone = store.undo(1, false)?;
+    assert_eq!(vec![2], undone.iter().map(|c| c.id).collect::<Vec<_>>());
+    assert_eq!(5, store.ledger()?.len());
+
+    // Undoing again goes further back rather than bringing the trade back.
+    let undone = store.undo(1, false)?;
+    assert_eq!(vec![1], undone.iter().map(|c| c.id).collect::<Vec<_>>());
+    assert_eq!(4, store.ledger()?.len());
+    let undo = store.history()?.pop().unwrap();
+    assert_eq!(
+        ("undo 1", 0, 1),
+        (&undo.command[..], undo.added, undo.removed)
+    );
+    assert!(store.undo(1, false).is_err());
+
+    // A file changed since its last recorded change is only reverted with force.
+    store.add_trade(buy(3.0))?;
+    append_lines(
+        &home.path().join("trades.tsv"),
+        &["IB	2016/03/03	Buy	Vanguard FTSE	4	100	0	1	1"],
+    )?;
+    let err = store.undo(1, false).unwrap_err();
+    assert_eq!(
+        "trades.tsv has changed since change 5, use --force to undo anyway",
+        err.to_string()
+    );
+    store.undo(1, true)?;
+    assert_eq!(4, store.ledger()?.len());
+    let commands: Vec<_> = store.history()?.into_iter().map(|c| c.command).collect();
+    assert_eq!(vec!["outside", "undo 5"], commands[5..]);
+    assert!(store.undo(5, false).is_err());
+    Ok(())
+}
+
+#[test]
+fn portfolios_migrate_to_sqlite_and_back() -> Result<()> {
+    use lupo::storage::StorageKind;
+
+    temp_store!(store, home, false);
+    two_accounts_portfolio(home.path())?;
+    let total = store.total(None)?;
+
+    assert_eq!(5, store.migrate(StorageKind::Sqlite)?);
+    let store = lupo::Store::open(home.path())?;
+    assert!(home.path().join("lupo.db").exists());
+    assert_eq!(total, store.total(None)?);
+
+    // The tsv files are left behind, the database is what changes from now on.
+    let (_, mut sell) = store.read_trades()?.pop().unwrap();
+    sell.r#type = lupo::TradeType::Sell;
+    sell.units = 5.0;
+    store.add_trade(sell)?;
+    // Rows are numbered as they were added, as lines are in files.
+    assert_eq!(5, store.read_trades()?.last().unwrap().0);
+    assert_eq!(5, lupo::Store::open(home.path())?.ledger()?.len());
+    let tsv = std::fs::read_to_string(home.path().join("trades.tsv"))
+        .chain_err(|| "Can't read trades file")?;
+    assert_eq!(5, tsv.lines().count());
+    store.undo(1, false)?;
+    assert_eq!(4, store.ledger()?.len());
+
+    store.migrate(StorageKind::Tsv)?;
+    let store = lupo::Store::open(home.path())?;
+    assert_eq!(total, store.total(None)?);
+    assert_eq!(4, store.ledger()?.len());
+    Ok(())
+}
+
+#[test]
+fn comments_are_kept_through_sqlite() -> Result<()> {
+    use lupo::storage::StorageKind;
+
+    temp_store!(store, home, false);
+    two_accounts_portfolio(home.path())?;
+    let trades = home.path().join("trades.tsv");
+    let commented = format!(
+        "# Trades of the IB and Fid accounts\n{}\n{}\n# Deposits\n{}\n{}\n# End\n",
+        lupo::TRADE_COLUMNS.join("\t"),
+        "IB\t2015/04/27\tTrIn\tCashIB\t10000\t1\t0\t1\t1\t",
+        "Fid\t2015/04/27\tTrIn\tCashFid\t10000\t1\t0\t1\t1\t",
+        "IB\t2016/01/04\tBuy\tVanguard FTSE\t10\t100\t1\t1\t1\t",
+    );
+    std::fs::write(&trades, &commented).chain_err(|| "Can't write trades file")?;
+
+    store.migrate(StorageKind::Sqlite)?;
+    std::fs::remove_file(&trades).chain_err(|| "Can't remove trades file")?;
+    let store = lupo::Store::open(home.path())?;
+    assert_eq!(3, store.ledger()?.len());
+    store.migrate(StorageKind::Tsv)?;
+    assert_eq!(
+        commented,
+        std::fs::read_to_string(&trades).chain_err(|| "Can't read trades file")?
+    );
+    Ok(())
+}
+
+// What the printer writes for the records in the format.
+fn printed<T: serde::Serialize + std::fmt::Display>(
+    format: lupo::output::Format,
+    records: &[T],
+) -> Result<String> {
+    let mut printer = lupo::output::Printer::to_writer(Vec::new(), format, "HEADER");
+    records.iter().for_each(|r| printer.print(r));
+    String::from_utf8(printer.finish()?).chain_err(|| "Output is not UTF-8")
+}
+
+#[test]
+fn records_are_printed_in_each_format() -> Result<()> {
+    use chrono::{TimeZone, Utc};
+    use lupo::output::Format;
+    use lupo::{PortLine, Trade, TradeType};
+
+    let trade = Trade {
+        account: "IB".to_string(),
+        date: Utc.ymd(2016, 3, 2).and_hms(0, 0, 0),
+        r#type: TradeType::Buy,
+        stock: "Vanguard FTSE".to_string(),
+        units: 10.0,
+        price: Some(100.5),
+        fees: None,
+        split: 1.0,
+        currency: Some(1.0),
+        lot: None,
+    };
+    let trades = [trade];
+    assert_eq!(
+        "[\n{\n  \"Account\": \"IB\",\n  \"Date\": \"2016/03/02\",\n  \"Type\": \"Buy\",\n  \
+         \"Stock\": \"Vanguard FTSE\",\n  \"Units\": 10.0,\n  \"Price\": 100.5,\n  \
+         \"Fees\": null,\n  \"Split\": 1.0,\n  \"Currency\": 1.0,\n  \"Lot\": null\n}\n]\n",
+        printed(Format::Json, &trades)?
+    );
+    assert_eq!(
+        "Account,Date,Type,Stock,Units,Price,Fees,Split,Currency,Lot\n\
+         IB,2016/03/02,Buy,Vanguard FTSE,10.0,100.5,,1.0,1.0,\n",
+        printed(Format::Csv, &trades)?
+    );
+    assert_eq!(
+        "Account\tDate\tType\tStock\tUnits\tPrice\tFees\tSplit\tCurrency\tLot\n\
+         IB\t2016/03/02\tBuy\tVanguard FTSE\t10.0\t100.5\t\t1.0\t1.0\t\n",
+        printed(Format::Tsv, &trades)?
+    );
+    assert_eq!(
+        format!("HEADER\n{}\n", trades[0]),
+        printed(Format::Table, &trades)?
+    );
+    assert_eq!("[]\n", printed::<Trade>(Format::Json, &[])?);
+
+    let line = PortLine {
+        account: "IB".to_string(),
+        ticker: Some("VWRL".to_string()),
+        name: "Vanguard FTSE".to_string(),
+        currency: "USD".to_string(),
+        traded_currency: "USD".to_string(),
+        asset: "Stock".to_string(),
+        group: "Equity".to_string(),
+        tags: "World".to_string(),
+        riskyness: "C".to_string(),
+        units: 10.0,
+        price: 110.0,
+        error: "".to_string(),
+        amount: 1100.0,
+        amount_perc: 1.0,
+        cost: 1000.0,
+        revenue: 1100.0,
+        divs: 0.0,
+        fees: 1.0,
+        basis: 1001.0,
+        basis_local: 1001.0,
+        realized: 0.0,
+        unrealized: 99.0,
+        last_trade: Utc.ymd(2016, 1, 4).and_hms(0, 0, 0),
+        gain: 99.0,
+        price_gain: 99.0,
+        fx_gain: 0.0,
+        tax_status: "LT".to_string(),
+        lt_units: 10.0,
+        st_units: 0.0,
+        next_lt: None,
+    };
+    let lines = [line];
+    let json: serde_json::Value = serde_json::from_str(&printed(Format::Json, &lines)?)
+        .chain_err(|| "Printed json doesn't parse")?;
+    assert_eq!(30, json[0].as_object().map_or(0, |o| o.len()));
+    assert_eq!("VWRL", json[0]["ticker"]);
+    assert_eq!(1100.0, json[0]["amount"]);
+    assert_eq!("2016/01/04", json[0]["last_trade"]);
+    assert!(json[0]["next_lt"].is_null());
+    let columns = "account,ticker,name,currency,traded_currency,asset,group,tags,riskyness,\
+                   units,price,error,amount,amount_perc,cost,revenue,divs,fees,basis,\
+                   basis_local,realized,unrealized,last_trade,gain,price_gain,fx_gain,\
+                   tax_status,lt_units,st_units,next_lt";
+    let values = "IB,VWRL,Vanguard FTSE,USD,USD,Stock,Equity,World,C,10.0,110.0,,1100.0,\
+                  1.0,1000.0,1100.0,0.0,1.0,1001.0,1001.0,0.0,99.0,2016/01/04,99.0,99.0,\
+                  0.0,LT,10.0,0.0,";
+    assert_eq!(
+        format!("{}\n{}\n", columns, values),
+        printed(Format::Csv, &lines)?
+    );
+    assert_eq!(
+        format!("{}\n{}\n", columns, values).replace(',', "\t"),
+        printed(Format::Tsv, &lines)?
+    );
+    Ok(())
+}